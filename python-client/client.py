@@ -151,7 +151,8 @@ def main():
                 server_message = client_socket.recv(1024).decode('utf-8').strip()
                 if server_message.startswith("START "):
                     # Parse the START command
-                    _, pixel_count, duration = server_message.split()
+                    # It also contains the absolute deadline of the slot (unix timestamp in ms), which we don't need
+                    _, pixel_count, duration, *_ = server_message.split()
                     pixel_count = int(pixel_count)
                     duration = int(duration)
                     print(f"Received START command: {pixel_count} pixels, {duration}ms")
@@ -1,28 +1,117 @@
+use std::fs;
+
+use anyhow::Context;
 use tokio::sync::{broadcast, mpsc, RwLock};
+use tracing::warn;
 
-use crate::{framebuffer::FrameBuffer, proto::WebSocketMessage};
+use crate::{
+    config::{Config, ProtectedRegion},
+    framebuffer::{FrameBuffer, PixelUpdate},
+    http_server::current_screen_png::PngCache,
+    journal::{unix_millis_now, Journal},
+    proto::{web_socket_message::Payload, WebSocketMessage},
+    snapshot::{path_with_suffix, restore_snapshot, RestoredSnapshot},
+};
 
 pub struct AppState {
     pub framebuffer: RwLock<FrameBuffer>,
 
+    /// [`None`] in case the journal is disabled
+    pub journal: Option<Journal>,
+
     pub ws_message_tx: mpsc::Sender<WebSocketMessage>,
     // TODO: Can we avoid cloning the [`Vec`] for every websocket connection?
     // Maybe have an Arc here?
     // See https://www.reddit.com/r/rust/comments/ms8yjz/how_to_send_a_slice_through_a_channel_confused/
     pub compressed_ws_message_tx: broadcast::Receiver<Vec<u8>>,
+
+    pub png_cache: PngCache,
+
+    /// Parts of the canvas only some users are allowed to paint in, see [`crate::config::CanvasConfig`]
+    pub protected_regions: Vec<ProtectedRegion>,
 }
 
 impl AppState {
+    /// Creates the app state, restoring the canvas from the last snapshot (if any) and replaying the changes recorded
+    /// in the journal since then.
     pub fn new(
-        width: u16,
-        height: u16,
+        config: &Config,
         ws_message_tx: mpsc::Sender<WebSocketMessage>,
         compressed_ws_message_tx: broadcast::Receiver<Vec<u8>>,
-    ) -> Self {
-        Self {
-            framebuffer: RwLock::new(FrameBuffer::new(width, height)),
+    ) -> anyhow::Result<Self> {
+        let empty_canvas = || FrameBuffer::new(config.canvas.width, config.canvas.height);
+        let (mut framebuffer, snapshot_taken_at_ms) =
+            match restore_snapshot(&config.snapshot, &config.canvas)
+                .context("Failed to restore canvas from snapshot")?
+            {
+                RestoredSnapshot::Canvas {
+                    framebuffer,
+                    taken_at_ms,
+                } => (framebuffer, taken_at_ms),
+                RestoredSnapshot::Missing => (empty_canvas(), 0),
+                RestoredSnapshot::Discarded => {
+                    let journal_file = &config.journal.file;
+                    if journal_file.exists() {
+                        // Otherwise we would replay everything painted onto the discarded canvas
+                        let discarded_file = path_with_suffix(journal_file, ".discarded");
+                        warn!(
+                            file = %journal_file.display(),
+                            discarded_file = %discarded_file.display(),
+                            "The snapshot was discarded, discarding the journal as well"
+                        );
+                        fs::rename(journal_file, &discarded_file).with_context(|| {
+                            format!(
+                                "Failed to move discarded journal file {} to {}",
+                                journal_file.display(),
+                                discarded_file.display()
+                            )
+                        })?;
+                    }
+                    (empty_canvas(), 0)
+                }
+            };
+
+        let journal = if config.journal.enabled {
+            Some(
+                Journal::recover(&config.journal.file, &mut framebuffer, snapshot_taken_at_ms)
+                    .context("Failed to recover canvas from journal")?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Self {
+            framebuffer: RwLock::new(framebuffer),
+            journal,
             ws_message_tx,
             compressed_ws_message_tx,
-        }
+            png_cache: Default::default(),
+            protected_regions: config.canvas.protected_regions.clone(),
+        })
+    }
+
+    /// Paints the given pixels, records them in the journal and sends them to all websockets
+    pub async fn paint(&self, client: &str, painted: &[PixelUpdate]) -> anyhow::Result<()> {
+        let client_painting = {
+            let mut framebuffer = self.framebuffer.write().await;
+            let client_painting = framebuffer.set_multi(client, painted);
+
+            // We journal while holding the write lock, so that the order of the journal matches the order the pixels
+            // were painted in. Appending only queues the entry, so we don't wait for the disk here.
+            if let Some(journal) = &self.journal {
+                journal.append(unix_millis_now(), &client_painting);
+            }
+
+            client_painting
+        };
+
+        self.ws_message_tx
+            .send(WebSocketMessage {
+                payload: Some(Payload::ClientPainting(client_painting)),
+            })
+            .await
+            .context("Failed to send update to websocket message channel")?;
+
+        Ok(())
     }
 }
@@ -1,120 +1,238 @@
-use std::time::Duration;
+use std::{
+    iter, mem,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use futures::{SinkExt, StreamExt};
 use nom::Finish;
-use tokio::{net::TcpStream, select, sync::mpsc};
-use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tokio::{
+    net::TcpStream,
+    select,
+    sync::{oneshot, watch},
+    time::{sleep_until, Instant},
+};
+use tokio_util::codec::{Framed, LinesCodecError};
 use tracing::{trace, warn};
 
 use super::{
-    parser::{parse_request, Request, Response},
+    codec::{ClientCodec, ClientCodecError, ClientFrame, ProtocolMode},
+    connection_state::{ConnectionState, Event, IllegalTransition},
+    error_code::ErrorCode,
+    help,
+    parser::{
+        explain_invalid_request, find_command, parse_request, InvalidRequest, Request, Response,
+        CAPABILITIES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    shape::Shape,
     user_manager::UserManager,
-    user_scheduler::UserScheduler,
-    HELP_TEXT, MAX_INPUT_LINE_LENGTH,
+    user_scheduler::{Registration, UserScheduler},
+    violation_tracker::{Offender, Sentence, ViolationTracker},
+};
+use crate::{
+    app_state::AppState,
+    config::{Config, Penalty},
+    framebuffer::PixelUpdate,
+    metrics,
 };
-use crate::{app_state::AppState, framebuffer::PixelUpdate};
 
 pub enum SlotEvent {
-    SlotStart,
+    /// Derived from the [`SlotStatus`], see [`ClientConnection::pending_slot_event`]
+    SlotStart(Slot),
+    /// Derived from the [`SlotStatus`], see [`ClientConnection::pending_slot_event`]
     SlotEnd,
 }
 
+/// Why the [`UserScheduler`] tells a connection to close
+#[derive(Clone, Copy, Debug)]
+pub enum CloseReason {
+    /// The same user logged in on another connection, which replaces this connection
+    Replaced,
+
+    /// An admin closed the connection
+    Kicked,
+}
+
+/// Slots of a user as published by the [`UserScheduler`].
+///
+/// In contrast to single events the status can not get lost while the connection is busy (e.g. painting). The
+/// connection compares it with the slot it knows about instead, to find out which slots started or ended meanwhile.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SlotStatus {
+    /// Number of slots the user got so far, increased whenever a slot starts
+    pub epoch: u64,
+
+    /// The slot `epoch` of the user, in case it is running right now
+    pub slot: Option<Slot>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Slot {
+    pub limits: SlotLimits,
+
+    /// Unix timestamp in milliseconds at which the slot ends
+    pub deadline_unix_ms: u64,
+}
+
+/// Limits of a single slot, which can differ between slots in case the slot duration is adaptive
+#[derive(Clone, Copy, Debug)]
+pub struct SlotLimits {
+    pub max_pixels: usize,
+    pub duration: Duration,
+}
+
 pub struct ClientConnection<'a> {
     user_manager: &'a UserManager,
     user_scheduler: &'a UserScheduler,
+    violation_tracker: &'a ViolationTracker,
     shared_state: &'a AppState,
+    config: &'a Config,
+    peer_ip: IpAddr,
 
-    slot_tx: mpsc::Sender<SlotEvent>,
-    slot_rx: mpsc::Receiver<SlotEvent>,
-    max_pixels_per_slot: usize,
-    slot_duration: Duration,
+    /// Handed to the scheduler on login, [`None`] afterwards
+    close_tx: Option<oneshot::Sender<CloseReason>>,
+    close_rx: oneshot::Receiver<CloseReason>,
+    slot_status_tx: watch::Sender<SlotStatus>,
+    slot_status_rx: watch::Receiver<SlotStatus>,
     painted: Vec<PixelUpdate>,
 
-    width: u16,
-    height: u16,
+    /// Shared with the scheduler, which uses it to detect idle users
+    painted_in_slot: Arc<AtomicUsize>,
 
     // State
+    state: ConnectionState,
+
+    /// [`SlotStatus::epoch`] of the last slot we processed the start of
+    slot_epoch: u64,
     current_username: Option<String>,
-    currently_in_slot: bool,
-    painting_finished: bool,
+    current_slot_limits: SlotLimits,
+    current_slot_started_at: Option<Instant>,
+
+    /// The slot ended without a `DONE`, which we still accept until this deadline
+    grace_deadline: Option<Instant>,
     current_pixel_count: usize,
+
+    /// The client was penalized for a rule violation, so we ignore its pixels and `DONE` until the next slot starts
+    penalized: bool,
 }
 
 impl<'a> ClientConnection<'a> {
-    #[allow(clippy::too_many_arguments)]
     pub fn new(
         user_manager: &'a UserManager,
         user_scheduler: &'a UserScheduler,
+        violation_tracker: &'a ViolationTracker,
         shared_state: &'a AppState,
-        max_pixels_per_slot: usize,
-        slot_duration: Duration,
-        width: u16,
-        height: u16,
+        config: &'a Config,
+        peer_ip: IpAddr,
     ) -> Self {
-        let (slot_tx, slot_rx) = mpsc::channel(1);
+        let (close_tx, close_rx) = oneshot::channel();
+        let (slot_status_tx, slot_status_rx) = watch::channel(SlotStatus::default());
 
         Self {
             user_manager,
             user_scheduler,
+            violation_tracker,
             shared_state,
-            slot_tx,
-            slot_rx,
-            max_pixels_per_slot,
-            slot_duration,
+            config,
+            peer_ip,
+            close_tx: Some(close_tx),
+            close_rx,
+            slot_status_tx,
+            slot_status_rx,
             painted: Default::default(),
-            width,
-            height,
+            painted_in_slot: Default::default(),
+            state: ConnectionState::Connected,
+            slot_epoch: 0,
             current_username: None,
-            currently_in_slot: false,
-            painting_finished: false,
+            current_slot_limits: SlotLimits {
+                max_pixels: config.scheduler.max_pixels_per_slot,
+                duration: config.scheduler.slot_duration,
+            },
+            current_slot_started_at: None,
+            grace_deadline: None,
             current_pixel_count: 0,
+            penalized: false,
         }
     }
 
     pub async fn run(&mut self, socket: &mut TcpStream) -> anyhow::Result<()> {
-        let mut framed = Framed::new(
-            socket,
-            LinesCodec::new_with_max_length(MAX_INPUT_LINE_LENGTH),
-        );
+        let max_input_line_length = self.config.ascii_server.max_input_line_length;
+        let mut framed = Framed::new(socket, ClientCodec::new(max_input_line_length));
 
         loop {
             enum Next {
-                ClientInput(Option<Result<String, LinesCodecError>>),
-                SlotEvent(Option<SlotEvent>),
+                ClientInput(Option<Result<ClientFrame, ClientCodecError>>),
+                SlotEvent(SlotEvent),
+                SlotStatusChanged,
+                Close(Result<CloseReason, oneshot::error::RecvError>),
+                GracePeriodOver,
             }
 
-            let next = select! {
-                // Cancellation safety: According to [`Framed`], [`tokio_stream::StreamExt::next`] is cancellation safe
-                line = framed.next() => Next::ClientInput(line),
-                // Cancellation safety: [`tokio::sync::mpsc::Receiver::recv`] is cancellation safe
-                slot_event = self.slot_rx.recv() => Next::SlotEvent(slot_event),
+            // During the grace period slot changes are not processed, so that e.g. the start of the next slot is only
+            // processed after the late `DONE`
+            let grace_deadline = self.grace_deadline;
+            let pending_slot_event = if grace_deadline.is_none() {
+                self.pending_slot_event()
+            } else {
+                None
+            };
+            let next = match pending_slot_event {
+                Some(slot_event) => Next::SlotEvent(slot_event),
+                None => select! {
+                    // Cancellation safety: According to [`Framed`], [`tokio_stream::StreamExt::next`] is cancellation safe
+                    frame = framed.next() => Next::ClientInput(frame),
+                    // Cancellation safety: Polling a [`oneshot::Receiver`] by reference is cancellation safe. This is
+                    // also polled during the grace period, so that e.g. kicks are never delayed.
+                    reason = &mut self.close_rx, if !self.close_rx.is_terminated() => Next::Close(reason),
+                    // Cancellation safety: [`tokio::sync::watch::Receiver::changed`] is cancellation safe. It can not
+                    // fail, as we keep a sender ourselves.
+                    Ok(()) = self.slot_status_rx.changed(), if grace_deadline.is_none() => Next::SlotStatusChanged,
+                    // Cancellation safety: Sleeping has no side effects
+                    _ = sleep_until(grace_deadline.unwrap_or_else(Instant::now)), if grace_deadline.is_some() => Next::GracePeriodOver,
+                },
             };
 
             // We need to store the current line, as the "request" variables lifetime is bound to it
             let mut _current_line = String::new();
             let response = match next {
                 // User send some input
-                Next::ClientInput(Some(line)) => {
-                    _current_line = match line {
-                        Ok(line) => line,
-                        Err(LinesCodecError::MaxLineLengthExceeded) => {
+                Next::ClientInput(Some(frame)) => {
+                    let frame = match frame {
+                        Ok(frame) => frame,
+                        Err(ClientCodecError::Lines(LinesCodecError::MaxLineLengthExceeded)) => {
                             framed
-                                .send(format!("ERROR The request line was too long. You can send at a maximum {MAX_INPUT_LINE_LENGTH} characters before you need to send a newline"))
+                                .send(format!("ERROR {} {max_input_line_length} The request line was too long. You can send at a maximum {max_input_line_length} characters before you need to send a newline", ErrorCode::LineTooLong))
                                 .await
                                 .context("Failed to send response to client")?;
                             return Ok(());
                         }
-                        Err(err) => {
-                            Err(err).context("Failed to read next line from framed LinesCodec")?
+                        Err(ClientCodecError::UnknownOpcode(opcode)) => {
+                            framed
+                                .send(format!("ERROR {} {opcode:#04x} Unknown binary opcode {opcode:#04x}. After switching to the binary protocol you can only send pixels and DONE", ErrorCode::UnknownOpcode))
+                                .await
+                                .context("Failed to send response to client")?;
+                            return Ok(());
                         }
+                        Err(err) => Err(err).context("Failed to read next frame from client")?,
                     };
-                    if _current_line.is_empty() {
-                        continue;
-                    }
 
-                    let request =
-                        Self::parse_request_report_errors(&_current_line, &mut framed).await?;
+                    let request = match frame {
+                        ClientFrame::Line(line) => {
+                            _current_line = line;
+                            if _current_line.is_empty() {
+                                continue;
+                            }
+
+                            Self::parse_request_report_errors(&_current_line, &mut framed).await?
+                        }
+                        // Binary requests don't need to be parsed
+                        ClientFrame::Request(request) => Some(request),
+                    };
                     trace!(?request, "Got request");
 
                     match request {
@@ -129,41 +247,79 @@ impl<'a> ClientConnection<'a> {
                     // The client closed the connection
                     return Ok(());
                 }
-                Next::SlotEvent(Some(SlotEvent::SlotStart)) => {
-                    if self.currently_in_slot {
-                        warn!("Received slot start, but was already in slot. Ignoring it");
-                        None
-                    } else {
-                        self.currently_in_slot = true;
-                        self.painting_finished = false;
+                // The changes are picked up by `pending_slot_event` in the next iteration
+                Next::SlotStatusChanged => None,
+                Next::SlotEvent(SlotEvent::SlotStart(Slot {
+                    limits,
+                    deadline_unix_ms,
+                })) => match self.state.on(Event::SlotStart) {
+                    Ok(state) => {
+                        self.state = state;
+                        self.current_slot_limits = limits;
+                        self.current_slot_started_at = Some(Instant::now());
                         self.current_pixel_count = 0;
+                        self.penalized = false;
 
                         Some(Response::Start {
-                            max_pixels_per_slot: self.max_pixels_per_slot,
-                            slot_duration: self.slot_duration,
+                            max_pixels_per_slot: limits.max_pixels,
+                            slot_duration: limits.duration,
+                            deadline_unix_ms,
                         })
                     }
-                }
-                Next::SlotEvent(Some(SlotEvent::SlotEnd)) => {
-                    if !self.currently_in_slot {
-                        warn!("Received slot end, but was not in slot. Ignoring it");
+                    Err(_) => {
+                        warn!(state = ?self.state, "Received slot start in unexpected state. Ignoring it");
                         None
-                    } else {
-                        self.currently_in_slot = false;
-
-                        if self.painting_finished {
+                    }
+                },
+                Next::SlotEvent(SlotEvent::SlotEnd) => {
+                    let grace_period = !self.config.scheduler.grace_period.is_zero();
+                    match self.state.on(Event::SlotEnd { grace_period }) {
+                        Ok(state) => match (mem::replace(&mut self.state, state), state) {
+                            (_, ConnectionState::GracePeriod) => {
+                                // The client did not send DONE yet, but it might still be on its way
+                                self.grace_deadline =
+                                    Some(Instant::now() + self.config.scheduler.grace_period);
+                                None
+                            }
+                            (ConnectionState::Painting, _) => {
+                                // The client did not send DONE in time
+                                Some(Response::SlotNotClosedInTime {
+                                    slot_duration: self.current_slot_limits.duration,
+                                    grace_period: self.config.scheduler.grace_period,
+                                    sentence: self.penalize().await?,
+                                })
+                            }
                             // Client did everything right, nothing to do
+                            _ => None,
+                        },
+                        Err(_) => {
+                            warn!(state = ?self.state, "Received slot end in unexpected state. Ignoring it");
                             None
-                        } else {
-                            // The client did not send DONE in time
+                        }
+                    }
+                }
+                Next::GracePeriodOver => {
+                    self.grace_deadline = None;
+                    match self.state.on(Event::GracePeriodOver) {
+                        Ok(state) => {
+                            // The client did not send DONE in time, not even within the grace period
+                            self.state = state;
                             Some(Response::SlotNotClosedInTime {
-                                slot_duration: self.slot_duration,
+                                slot_duration: self.current_slot_limits.duration,
+                                grace_period: self.config.scheduler.grace_period,
+                                sentence: self.penalize().await?,
                             })
                         }
+                        Err(_) => {
+                            warn!(state = ?self.state, "Grace period ended in unexpected state. Ignoring it");
+                            None
+                        }
                     }
                 }
-                Next::SlotEvent(None) => {
-                    // The client closed the connection
+                Next::Close(Ok(CloseReason::Replaced)) => Some(Response::LoginReplaced),
+                Next::Close(Ok(CloseReason::Kicked)) => Some(Response::Kicked),
+                Next::Close(Err(_)) => {
+                    // The scheduler dropped our registration without telling us why
                     return Ok(());
                 }
             };
@@ -184,30 +340,117 @@ impl<'a> ClientConnection<'a> {
         }
     }
 
+    /// Compares the slot published by the scheduler with the slot we know about and returns the next thing that happened
+    /// meanwhile. Slots that started and ended while we were not looking (e.g. during the grace period) are skipped.
+    fn pending_slot_event(&mut self) -> Option<SlotEvent> {
+        let status = *self.slot_status_rx.borrow_and_update();
+        let in_slot = matches!(
+            self.state,
+            ConnectionState::Painting | ConnectionState::Finished
+        );
+        if in_slot && (status.epoch != self.slot_epoch || status.slot.is_none()) {
+            return Some(SlotEvent::SlotEnd);
+        }
+
+        match status.slot {
+            Some(slot) if status.epoch != self.slot_epoch => {
+                self.slot_epoch = status.epoch;
+                Some(SlotEvent::SlotStart(slot))
+            }
+            _ => None,
+        }
+    }
+
+    /// Removes the user from the scheduler, so that a closed connection does not occupy any slots
+    pub async fn unregister(&self) {
+        if let Some(username) = &self.current_username {
+            self.user_scheduler
+                .unregister_user(username, &self.slot_status_tx)
+                .await;
+        }
+    }
+
+    /// Records a rule violation of the current user and applies the penalty.
+    ///
+    /// The pixels of the current slot are discarded. In case the connection is not closed, further pixels are ignored
+    /// until the next slot starts, so that the already sent pixels don't result in further offenses.
+    async fn penalize(&mut self) -> anyhow::Result<Sentence> {
+        let username = self.username()?;
+        let sentence = self
+            .violation_tracker
+            .record_offense(username, self.peer_ip)
+            .await;
+        if let Penalty::SkipSlots(slots) = sentence.penalty {
+            self.user_scheduler.skip_slots(username, slots).await;
+        }
+
+        self.painted.clear();
+        self.penalized = true;
+        self.grace_deadline = None;
+        self.state = match self.state {
+            ConnectionState::Painting => ConnectionState::Finished,
+            ConnectionState::GracePeriod => ConnectionState::Waiting,
+            state => state,
+        };
+
+        Ok(sentence)
+    }
+
+    /// Returns the response to a request that is not allowed in the current state
+    async fn reject(&mut self, illegal: IllegalTransition) -> anyhow::Result<Response> {
+        Ok(match illegal {
+            IllegalTransition::LoginNeeded => Response::LoginNeeded,
+            IllegalTransition::AlreadyLoggedIn => Response::AlreadyLoggedIn,
+            IllegalTransition::NotYourSlot => Response::NotYourSlot {
+                sentence: self.penalize().await?,
+            },
+            IllegalTransition::PixelAfterDone => Response::PixelAfterDone {
+                sentence: self.penalize().await?,
+            },
+            IllegalTransition::DoneOutsideSlot => Response::DoneOutsideSlot,
+            IllegalTransition::AlreadyDone => Response::AlreadyDone,
+            IllegalTransition::UnexpectedSlotEvent => {
+                bail!(
+                    "Requests can not result in unexpected slot events. This should never happen!"
+                )
+            }
+        })
+    }
+
+    /// Name of the logged in user, must only be called in states that require a login
+    fn username(&self) -> anyhow::Result<&str> {
+        self.current_username
+            .as_deref()
+            .context("The current username is not know. This should never happen!")
+    }
+
     #[inline(always)]
     async fn parse_request_report_errors<'line>(
         line: &'line str,
-        framed: &mut Framed<&mut TcpStream, LinesCodec>,
+        framed: &mut Framed<&mut TcpStream, ClientCodec>,
     ) -> anyhow::Result<Option<Request<'line>>> {
-        match parse_request(line).finish() {
-            Ok(("", request)) => Ok(Some(request)),
-            Ok((remaining, request)) => {
-                framed
-                    .send(format!("ERROR The request {line:?} could be parsed to {request:?}, but it had remaining bytes: {remaining:?}"))
-                    .await
-                    .context("Failed to send response to client")?;
+        if let Ok(("", request)) = parse_request(line).finish() {
+            return Ok(Some(request));
+        }
 
-                Ok(None)
-            }
-            Err(err) => {
-                framed
-                    .send(format!("ERROR Invalid request {line:?}: {err:?}"))
-                    .await
-                    .context("Failed to send response to client")?;
+        // Requests with remaining bytes are as invalid as requests that could not be parsed at all
+        let error = match explain_invalid_request(line) {
+            InvalidRequest::UnknownCommand { command } => format!(
+                "ERROR {} Unknown command {command:?}, send \"HELP\" for a list of all commands",
+                ErrorCode::UnknownCommand
+            ),
+            InvalidRequest::InvalidArguments { command } => format!(
+                "ERROR {} Invalid arguments, usage: {}",
+                ErrorCode::InvalidArguments,
+                command.usages.join(" or ")
+            ),
+        };
+        framed
+            .send(error)
+            .await
+            .context("Failed to send response to client")?;
 
-                Ok(None)
-            }
-        }
+        Ok(None)
     }
 
     async fn determine_response(
@@ -215,14 +458,44 @@ impl<'a> ClientConnection<'a> {
         request: Request<'_>,
     ) -> anyhow::Result<Option<Response>> {
         Ok(match request {
-            Request::Help => Some(Response::Help),
+            Request::Hello { version } => {
+                let version = version.unwrap_or(PROTOCOL_VERSION);
+                Some(if version < MIN_PROTOCOL_VERSION {
+                    Response::UnsupportedVersion { version }
+                } else {
+                    Response::Hello {
+                        version: version.min(PROTOCOL_VERSION),
+                    }
+                })
+            }
+            Request::Help { command: None } => Some(Response::Help { command: None }),
+            Request::Help {
+                command: Some(name),
+            } => Some(match find_command(name) {
+                Some(command) => Response::Help {
+                    command: Some(command),
+                },
+                None => Response::UnknownCommand {
+                    command: name.to_owned(),
+                },
+            }),
             Request::Size => Some(Response::Size {
-                width: self.width,
-                height: self.height,
+                width: self.config.canvas.width,
+                height: self.config.canvas.height,
             }),
             Request::Login { username, password } => {
-                if self.current_username.is_some() {
-                    return Ok(Some(Response::AlreadyLoggedIn));
+                let state = match self.state.on(Event::Login) {
+                    Ok(state) => state,
+                    Err(illegal) => return self.reject(illegal).await.map(Some),
+                };
+                // The IP address could have been banned after the connection was opened
+                for offender in [
+                    Offender::User(username.to_owned()),
+                    Offender::Ip(self.peer_ip),
+                ] {
+                    if let Some(ban) = self.violation_tracker.active_ban(&offender).await {
+                        return Ok(Some(Response::Banned { ban }));
+                    }
                 }
 
                 if !self
@@ -234,13 +507,27 @@ impl<'a> ClientConnection<'a> {
                     return Ok(Some(Response::LoginFailed));
                 }
 
-                self.current_username = Some(username.to_owned());
-
-                self.user_scheduler
-                    .register_user(username, self.slot_tx.clone())
-                    .await;
-
-                Some(Response::LoginSucceeded)
+                match self
+                    .user_scheduler
+                    .register_user(
+                        username,
+                        self.peer_ip,
+                        self.slot_status_tx.clone(),
+                        self.close_tx
+                            .take()
+                            .context("Connection was already registered")?,
+                        self.painted_in_slot.clone(),
+                        self.config.ascii_server.duplicate_login_policy,
+                    )
+                    .await
+                {
+                    Registration::Registered => {
+                        self.state = state;
+                        self.current_username = Some(username.to_owned());
+                        Some(Response::LoginSucceeded)
+                    }
+                    Registration::AlreadyConnected => Some(Response::AlreadyConnected),
+                }
             }
             Request::GetPixel { x, y } => self
                 .shared_state
@@ -249,70 +536,198 @@ impl<'a> ClientConnection<'a> {
                 .await
                 .get(x, y)
                 .map(|rgba| Response::GetPixel { x, y, rgba }),
-            Request::SetPixel { x, y, rgba } => {
-                if self.current_username.is_none() {
-                    return Ok(Some(Response::LoginNeeded));
-                }
-                if !self.currently_in_slot {
-                    return Ok(Some(Response::NotYourSlot));
-                }
-                if self.current_pixel_count >= self.max_pixels_per_slot {
-                    return Ok(Some(Response::QuotaExceeded {
-                        max_pixels_per_slot: self.max_pixels_per_slot,
-                    }));
-                }
-
-                self.current_pixel_count += 1;
-                self.painted.push(PixelUpdate { x, y, rgba });
-
-                None
+            Request::SetPixel { x, y, rgba, alpha } => {
+                self.set_pixels(Shape::pixel(x, y), iter::repeat((rgba, alpha)))
+                    .await?
+            }
+            Request::SetShape { shape, rgba, alpha } => {
+                self.set_pixels(shape, iter::repeat((rgba, alpha))).await?
+            }
+            Request::SetSpan { x, y, colors } => {
+                let shape = Shape::Rect {
+                    x,
+                    y,
+                    // The parser reads at most u16::MAX colors
+                    width: colors.len() as u16,
+                    height: 1,
+                };
+                self.set_pixels(shape, colors.into_iter()).await?
             }
             Request::Done => {
-                self.painting_finished = true;
+                if self.penalized {
+                    return Ok(Some(Response::Penalized));
+                }
+                let state = match self.state.on(Event::Done) {
+                    Ok(state) => state,
+                    Err(illegal) => return self.reject(illegal).await.map(Some),
+                };
+                let elapsed = self
+                    .current_slot_started_at
+                    .map(|started_at| started_at.elapsed())
+                    .unwrap_or_default();
+
+                // In case the slot already ended and we only waited for the DONE, the slot is over now
+                let late = mem::replace(&mut self.state, state) == ConnectionState::GracePeriod;
+                self.grace_deadline = None;
+                if late {
+                    metrics::LATE_DONES.inc();
+                    if self.config.scheduler.discard_late_pixels {
+                        self.painted.clear();
+                    }
+                }
 
                 let num_pixels = self.painted.len();
-                let ws_update = self.shared_state.framebuffer.write().await.set_multi(
-                    self.current_username
-                        .as_ref()
-                        .context("The current username is not know. This should never happen!")?,
-                    &self.painted,
-                );
+                let username = self.username()?;
+                self.shared_state
+                    .paint(username, &self.painted)
+                    .await
+                    .context("Failed to paint pixels")?;
+                self.painted_in_slot
+                    .fetch_add(num_pixels, Ordering::Relaxed);
+                metrics::PAINTED_PIXELS.inc_by(num_pixels as u64);
 
                 self.painted.clear();
 
-                self.shared_state
-                    .ws_message_tx
-                    .send(ws_update)
-                    .await
-                    .context("Failed to send update to websocket message channel")?;
+                Some(Response::Done {
+                    num_pixels,
+                    elapsed,
+                    late,
+                })
+            }
+            Request::Protocol { mode } => {
+                if let Err(illegal) = self.state.on(Event::SwitchProtocol) {
+                    return self.reject(illegal).await.map(Some);
+                }
 
-                Some(Response::Done { num_pixels })
+                Some(Response::ProtocolSwitched { mode })
             }
         })
     }
 
+    /// Sets all pixels of the given shape, which is accepted or rejected as a whole. Every pixel counts against the
+    /// quota, exactly as if it was set on its own.
+    async fn set_pixels(
+        &mut self,
+        shape: Shape,
+        colors: impl Iterator<Item = (u32, u8)>,
+    ) -> anyhow::Result<Option<Response>> {
+        if self.penalized {
+            return Ok(Some(Response::Penalized));
+        }
+        if let Err(illegal) = self.state.on(Event::SetPixel) {
+            return self.reject(illegal).await.map(Some);
+        }
+        let (width, height) = (self.config.canvas.width, self.config.canvas.height);
+        let (max_x, max_y) = shape.max_corner();
+        if max_x >= width as u32 || max_y >= height as u32 {
+            return Ok(Some(Response::PixelOutOfBounds {
+                x: max_x,
+                y: max_y,
+                width,
+                height,
+            }));
+        }
+        // Checked before looking at the single pixels, so that huge shapes are rejected right away
+        let num_pixels = shape.num_pixels();
+        let max_pixels_per_slot = self.current_slot_limits.max_pixels;
+        if self.current_pixel_count + num_pixels > max_pixels_per_slot {
+            return Ok(Some(Response::QuotaExceeded {
+                max_pixels_per_slot,
+                sentence: self.penalize().await?,
+            }));
+        }
+        let username = self.username()?;
+        if let Some((x, y, region)) = shape.pixels().find_map(|(x, y)| {
+            self.shared_state
+                .protected_regions
+                .iter()
+                .find(|region| region.contains(x, y) && !region.allows(username))
+                .map(|region| (x, y, region))
+        }) {
+            return Ok(Some(Response::PixelProtected {
+                x,
+                y,
+                region: region.name.clone(),
+            }));
+        }
+
+        self.current_pixel_count += num_pixels;
+        self.painted.extend(
+            shape
+                .pixels()
+                .zip(colors)
+                .map(|((x, y), (rgba, alpha))| PixelUpdate { x, y, rgba, alpha }),
+        );
+
+        Ok(None)
+    }
+
     /// Sends the given response to the client and returns if the connection should be closed
     pub async fn send_response(
         &self,
         response: Response,
-        framed: &mut Framed<&mut TcpStream, LinesCodec>,
+        framed: &mut Framed<&mut TcpStream, ClientCodec>,
     ) -> anyhow::Result<bool> {
         let mut close_connection = false;
 
         match response {
-            Response::Help => framed.send(HELP_TEXT).await,
+            Response::Hello { version } => {
+                framed
+                    .send(format!("HELLO {version} {}", CAPABILITIES.join(" ")))
+                    .await
+            }
+            Response::UnsupportedVersion { version } => {
+                framed
+                    .send(format!(
+                        "ERROR {} {MIN_PROTOCOL_VERSION} {PROTOCOL_VERSION} Protocol version {version} is not supported, this server supports the versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}",
+                        ErrorCode::UnsupportedVersion
+                    ))
+                    .await
+            }
+            Response::Help { command: None } => framed.send(help::overview(self.config)).await,
+            Response::Help {
+                command: Some(command),
+            } => framed.send(help::details(command)).await,
+            Response::UnknownCommand { command } => {
+                framed
+                    .send(format!(
+                        "ERROR {} Unknown command {command:?}, send \"HELP\" for a list of all commands",
+                        ErrorCode::UnknownCommand
+                    ))
+                    .await
+            }
             Response::Size { width, height } => framed.send(format!("SIZE {width} {height}")).await,
             Response::LoginNeeded => {
                 close_connection = true;
-                framed.send("ERROR LOGIN NEEDED").await
+                framed.send(format!("ERROR {} Login needed, please send \"LOGIN <username> <password>\" first", ErrorCode::LoginNeeded)).await
             }
             Response::LoginSucceeded => framed.send("LOGIN SUCCEEDED").await,
             Response::LoginFailed => {
                 close_connection = true;
-                framed.send("ERROR LOGIN FAILED").await
+                framed.send(format!("ERROR {} Login failed, the username is taken and the password does not match", ErrorCode::LoginFailed)).await
+            }
+            Response::AlreadyConnected => {
+                close_connection = true;
+                framed
+                    .send(format!("ERROR {} This user is already logged in on another connection", ErrorCode::AlreadyConnected))
+                    .await
+            }
+            Response::LoginReplaced => {
+                close_connection = true;
+                framed
+                    .send(format!("ERROR {} This user logged in on another connection, which replaces this connection", ErrorCode::LoginReplaced))
+                    .await
+            }
+            Response::Banned { ban } => {
+                close_connection = true;
+                framed.send(format!("ERROR {} You are banned {ban}", ErrorCode::Banned)).await
+            }
+            Response::Kicked => {
+                close_connection = true;
+                framed.send(format!("ERROR {} You were kicked by an admin", ErrorCode::Kicked)).await
             }
             Response::AlreadyLoggedIn => {
-                framed.send("ERROR Already logged in").await
+                framed.send(format!("ERROR {} Already logged in", ErrorCode::AlreadyLoggedIn)).await
             }
             Response::GetPixel { x, y, rgba } => {
                 framed.send(format!("PX {x} {y} {rgba:06x}")).await
@@ -320,35 +735,413 @@ impl<'a> ClientConnection<'a> {
             Response::Start {
                 max_pixels_per_slot,
                 slot_duration,
+                deadline_unix_ms,
             } => {
                 framed
-                    .send(format!("START {} {}", max_pixels_per_slot, slot_duration.as_millis()))
+                    .send(format!(
+                        "START {} {} {}",
+                        max_pixels_per_slot,
+                        slot_duration.as_millis(),
+                        deadline_unix_ms
+                    ))
                     .await
             }
-            Response::Done { num_pixels } => framed.send(format!("DONE {num_pixels}")).await,
-            Response::NotYourSlot => {
-                close_connection = true;
+            Response::Done {
+                num_pixels,
+                elapsed,
+                late,
+            } => {
+                let late = if late { " LATE" } else { "" };
                 framed
-                    .send("ERROR It was not your time slot, please wait until you get a START command!")
+                    .send(format!("DONE {num_pixels} {}{late}", elapsed.as_millis()))
+                    .await
+            }
+            Response::NotYourSlot { sentence } => {
+                metrics::VIOLATIONS
+                    .with_label_values(&["not_your_slot"])
+                    .inc();
+                close_connection = sentence.penalty.closes_connection();
+                framed
+                    .send(format!("ERROR {} It was not your time slot, please wait until you get a START command! {sentence}", ErrorCode::NotYourSlot))
+                    .await
+            }
+            Response::PixelAfterDone { sentence } => {
+                metrics::VIOLATIONS
+                    .with_label_values(&["pixel_after_done"])
+                    .inc();
+                close_connection = sentence.penalty.closes_connection();
+                framed
+                    .send(format!("ERROR {} You already sent \"DONE\" in this slot, you can not paint any more pixels until your next START! {sentence}", ErrorCode::PixelAfterDone))
+                    .await
+            }
+            Response::DoneOutsideSlot => {
+                framed
+                    .send(format!("ERROR {} It is not your slot, so there is nothing to finish with \"DONE\"", ErrorCode::DoneOutsideSlot))
+                    .await
+            }
+            Response::AlreadyDone => {
+                framed
+                    .send(format!("ERROR {} You already sent \"DONE\" in this slot", ErrorCode::AlreadyDone))
                     .await
             }
             Response::QuotaExceeded {
                 max_pixels_per_slot,
+                sentence,
             } => {
-                close_connection = true;
+                metrics::VIOLATIONS
+                    .with_label_values(&["quota_exceeded"])
+                    .inc();
+                close_connection = sentence.penalty.closes_connection();
                 framed
-                    .send(&format!("ERROR Quota exceeded. You are only allowed to set {max_pixels_per_slot} pixels per slot, please play fair!"))
+                    .send(format!("ERROR {} {max_pixels_per_slot} Quota exceeded. You are only allowed to set {max_pixels_per_slot} pixels per slot, please play fair! {sentence}", ErrorCode::Quota))
                     .await
             }
-            Response::SlotNotClosedInTime { slot_duration } => {
-                close_connection = true;
+            Response::PixelOutOfBounds {
+                x,
+                y,
+                width,
+                height,
+            } => {
                 framed
-                    .send(&format!("ERROR Slot not closed in time. After you finished drawing your pixels you need to send \"DONE\" to signalize you are done. Your slot lasts {slot_duration:?}, you need to send \"DONE\" in that period of time (keep the network delay in mind)"))
+                    .send(format!("ERROR {} {x} {y} {width} {height} The pixel ({x}, {y}) is outside of the canvas. The canvas is {width}x{height} pixels, so x needs to be below {width} and y below {height}", ErrorCode::OutOfBounds))
+                    .await
+            }
+            Response::PixelProtected { x, y, region } => {
+                framed
+                    .send(format!("ERROR {} {x} {y} The pixel ({x}, {y}) is within the protected region {region:?}, which you are not allowed to paint in", ErrorCode::Protected))
+                    .await
+            }
+            Response::ProtocolSwitched { mode } => {
+                framed.codec_mut().set_mode(mode);
+                match mode {
+                    ProtocolMode::Ascii => framed.send("PROTOCOL ASCII").await,
+                    ProtocolMode::Binary => framed.send("PROTOCOL BINARY").await,
+                }
+            }
+            Response::SlotNotClosedInTime {
+                slot_duration,
+                grace_period,
+                sentence,
+            } => {
+                metrics::VIOLATIONS
+                    .with_label_values(&["slot_not_closed_in_time"])
+                    .inc();
+                close_connection = sentence.penalty.closes_connection();
+                framed
+                    .send(format!("ERROR {} {} {} Slot not closed in time. After you finished drawing your pixels you need to send \"DONE\" to signalize you are done. Your slot lasts {slot_duration:?}, you need to send \"DONE\" in that period of time (keep the network delay in mind, a late \"DONE\" is accepted for at most {grace_period:?}). {sentence}", ErrorCode::SlotNotClosed, slot_duration.as_millis(), grace_period.as_millis()))
                     .await
             },
+            Response::Penalized => {
+                framed
+                    .send(format!("ERROR {} You were penalized for a rule violation in this slot, so your pixels and \"DONE\" are ignored until your next START", ErrorCode::Penalized))
+                    .await
+            }
         }
         .context("Failed to send response to client")?;
 
         Ok(close_connection)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::*;
+    use crate::config::ProtectedRegion;
+
+    /// Everything a [`ClientConnection`] borrows from the server
+    struct Server {
+        user_manager: UserManager,
+        user_scheduler: UserScheduler,
+        violation_tracker: ViolationTracker,
+        shared_state: Arc<AppState>,
+        config: Config,
+    }
+
+    impl Server {
+        async fn new(mut config: Config) -> Self {
+            config.snapshot.enabled = false;
+            config.journal.enabled = false;
+            // Never written, as the tests don't log in
+            config.ascii_server.users_file =
+                std::env::temp_dir().join(format!("pixelstrom-{}-users.json", std::process::id()));
+            let (ws_message_tx, _) = mpsc::channel(1);
+            let (_, compressed_ws_message_rx) = broadcast::channel(1);
+            let shared_state = Arc::new(
+                AppState::new(&config, ws_message_tx, compressed_ws_message_rx)
+                    .expect("app state without snapshot and journal can be created"),
+            );
+
+            Self {
+                user_manager: UserManager::new_from_save_file(&config.ascii_server.users_file)
+                    .await
+                    .unwrap(),
+                user_scheduler: UserScheduler::new(shared_state.clone(), &config.scheduler),
+                violation_tracker: ViolationTracker::new(&config.violations),
+                shared_state,
+                config,
+            }
+        }
+
+        /// Connection that was just opened
+        fn connect(&self) -> ClientConnection<'_> {
+            ClientConnection::new(
+                &self.user_manager,
+                &self.user_scheduler,
+                &self.violation_tracker,
+                &self.shared_state,
+                &self.config,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+            )
+        }
+
+        /// Connection of the given user in the middle of its slot
+        fn painting(&self, username: &str) -> ClientConnection<'_> {
+            let mut connection = self.connect();
+            connection.state = ConnectionState::Painting;
+            connection.current_username = Some(username.to_owned());
+
+            connection
+        }
+    }
+
+    fn set_shape(shape: Shape) -> Request<'static> {
+        Request::SetShape {
+            shape,
+            rgba: 0xff0000,
+            alpha: u8::MAX,
+        }
+    }
+
+    #[tokio::test]
+    async fn pixels_outside_of_the_canvas_are_rejected() {
+        let server = Server::new(Config::default()).await;
+        let mut connection = server.painting("alice");
+        let (width, height) = (server.config.canvas.width, server.config.canvas.height);
+
+        let response = connection
+            .determine_response(Request::SetPixel {
+                x: width,
+                y: 0,
+                rgba: 0xff0000,
+                alpha: u8::MAX,
+            })
+            .await
+            .unwrap();
+        assert!(
+            matches!(response, Some(Response::PixelOutOfBounds { x, y: 0, .. }) if x == width as u32)
+        );
+
+        // Shapes are rejected as a whole, even if only their last pixel is outside
+        let response = connection
+            .determine_response(set_shape(Shape::Rect {
+                x: width - 2,
+                y: height - 1,
+                width: 3,
+                height: 1,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Response::PixelOutOfBounds { .. })));
+
+        // The far corner of the largest shape does not fit into 16 bits
+        let response = connection
+            .determine_response(set_shape(Shape::Rect {
+                x: u16::MAX,
+                y: u16::MAX,
+                width: u16::MAX,
+                height: u16::MAX,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            response,
+            Some(Response::PixelOutOfBounds { x, y, .. }) if x > u16::MAX as u32 && y > u16::MAX as u32
+        ));
+
+        assert!(connection.painted.is_empty());
+        assert!(!connection.penalized);
+        assert_eq!(connection.state, ConnectionState::Painting);
+    }
+
+    #[tokio::test]
+    async fn pixels_inside_of_the_canvas_are_accepted() {
+        let server = Server::new(Config::default()).await;
+        let mut connection = server.painting("alice");
+        let (width, height) = (server.config.canvas.width, server.config.canvas.height);
+
+        let response = connection
+            .determine_response(set_shape(Shape::Line {
+                x1: 0,
+                y1: 0,
+                x2: width - 1,
+                y2: height - 1,
+            }))
+            .await
+            .unwrap();
+        assert!(response.is_none());
+        assert_eq!(connection.painted.len(), width as usize);
+    }
+
+    /// Canvas with a 10x10 region at (100, 100) only alice may paint in
+    fn protected_config() -> Config {
+        let mut config = Config::default();
+        config.canvas.protected_regions.push(ProtectedRegion {
+            name: "Sponsor".to_owned(),
+            x: 100,
+            y: 100,
+            width: 10,
+            height: 10,
+            allowed_users: vec!["alice".to_owned()],
+        });
+
+        config
+    }
+
+    #[tokio::test]
+    async fn pixels_in_protected_regions_are_rejected() {
+        let server = Server::new(protected_config()).await;
+        let mut connection = server.painting("bob");
+
+        for (x, y) in [(100, 100), (109, 109)] {
+            let response = connection
+                .determine_response(set_shape(Shape::pixel(x, y)))
+                .await
+                .unwrap();
+            assert!(matches!(
+                response,
+                Some(Response::PixelProtected { x: px, y: py, region }) if (px, py) == (x, y) && region == "Sponsor"
+            ));
+        }
+
+        // A line crossing the region is rejected as a whole, reporting the first protected pixel
+        let response = connection
+            .determine_response(set_shape(Shape::Line {
+                x1: 90,
+                y1: 105,
+                x2: 120,
+                y2: 105,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            response,
+            Some(Response::PixelProtected { x: 100, y: 105, .. })
+        ));
+
+        // Rejected pixels don't count against the quota and are no offense
+        assert!(connection.painted.is_empty());
+        assert_eq!(connection.current_pixel_count, 0);
+        assert!(!connection.penalized);
+
+        // Right next to the region is fine
+        for (x, y) in [(99, 100), (110, 100), (100, 110)] {
+            let response = connection
+                .determine_response(set_shape(Shape::pixel(x, y)))
+                .await
+                .unwrap();
+            assert!(response.is_none());
+        }
+        assert_eq!(connection.painted.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn allowed_users_can_paint_in_protected_regions() {
+        let server = Server::new(protected_config()).await;
+        let mut connection = server.painting("alice");
+
+        let response = connection
+            .determine_response(set_shape(Shape::Rect {
+                x: 95,
+                y: 95,
+                width: 20,
+                height: 20,
+            }))
+            .await
+            .unwrap();
+        assert!(response.is_none());
+        assert_eq!(connection.painted.len(), 400);
+    }
+
+    #[tokio::test]
+    async fn hello_negotiates_the_protocol_version() {
+        let server = Server::new(Config::default()).await;
+        let mut connection = server.connect();
+
+        for (requested, negotiated) in [
+            (None, PROTOCOL_VERSION),
+            (Some(MIN_PROTOCOL_VERSION), MIN_PROTOCOL_VERSION),
+            (Some(PROTOCOL_VERSION), PROTOCOL_VERSION),
+            // Newer clients fall back to the newest version we support
+            (Some(PROTOCOL_VERSION + 1), PROTOCOL_VERSION),
+            (Some(u32::MAX), PROTOCOL_VERSION),
+        ] {
+            let response = connection
+                .determine_response(Request::Hello { version: requested })
+                .await
+                .unwrap();
+            assert!(
+                matches!(response, Some(Response::Hello { version }) if version == negotiated),
+                "{requested:?}: {response:?}"
+            );
+        }
+
+        let response = connection
+            .determine_response(Request::Hello {
+                version: Some(MIN_PROTOCOL_VERSION - 1),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            response,
+            Some(Response::UnsupportedVersion { version }) if version == MIN_PROTOCOL_VERSION - 1
+        ));
+
+        // HELLO works before and after the login and changes nothing
+        assert_eq!(connection.state, ConnectionState::Connected);
+        let mut connection = server.painting("alice");
+        let response = connection
+            .determine_response(Request::Hello { version: None })
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Response::Hello { .. })));
+        assert_eq!(connection.state, ConnectionState::Painting);
+    }
+
+    #[tokio::test]
+    async fn penalized_clients_are_told_that_they_are_ignored() {
+        let server = Server::new(Config::default()).await;
+        let mut connection = server.painting("alice");
+        connection.current_slot_limits.max_pixels = 10;
+
+        let response = connection
+            .determine_response(set_shape(Shape::Rect {
+                x: 0,
+                y: 0,
+                width: 11,
+                height: 1,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Response::QuotaExceeded { .. })));
+        assert!(connection.penalized);
+
+        // Every following request of the slot gets an answer, so that the client does not wait for one forever
+        let response = connection
+            .determine_response(set_shape(Shape::pixel(0, 0)))
+            .await
+            .unwrap();
+        assert!(matches!(response, Some(Response::Penalized)));
+        let response = connection.determine_response(Request::Done).await.unwrap();
+        assert!(matches!(response, Some(Response::Penalized)));
+
+        assert!(connection.painted.is_empty());
+        assert_eq!(
+            server.shared_state.framebuffer.read().await.get(0, 0),
+            Some(0)
+        );
+    }
+}
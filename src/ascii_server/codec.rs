@@ -0,0 +1,163 @@
+use std::{fmt, io};
+
+use prost::bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder, LinesCodec, LinesCodecError};
+
+use super::parser::Request;
+
+/// Sets a single pixel: `0x01`, x (u16 big endian), y (u16 big endian), r, g, b
+pub const OPCODE_SET_PIXEL: u8 = 0x01;
+const SET_PIXEL_LEN: usize = 1 + 2 + 2 + 3;
+
+/// Finishes painting, same as the ASCII `DONE`: `0x02`
+pub const OPCODE_DONE: u8 = 0x02;
+
+/// Blends a single pixel onto the canvas: `0x03`, x (u16 big endian), y (u16 big endian), r, g, b, a
+pub const OPCODE_BLEND_PIXEL: u8 = 0x03;
+const BLEND_PIXEL_LEN: usize = 1 + 2 + 2 + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolMode {
+    /// Newline separated text commands
+    Ascii,
+
+    /// Fixed-size binary records for painting, see the `OPCODE_*` constants.
+    /// Responses are still sent as text lines.
+    Binary,
+}
+
+/// A frame sent by the client
+pub enum ClientFrame {
+    /// A text line that still needs to be parsed
+    Line(String),
+
+    /// A request that was already decoded from its binary representation
+    Request(Request<'static>),
+}
+
+#[derive(Debug)]
+pub enum ClientCodecError {
+    Lines(LinesCodecError),
+    UnknownOpcode(u8),
+}
+
+/// Codec used for the client connection.
+///
+/// It starts with the ASCII protocol and can be switched to the binary protocol using [`ClientCodec::set_mode`].
+pub struct ClientCodec {
+    lines: LinesCodec,
+    mode: ProtocolMode,
+}
+
+impl ClientCodec {
+    pub fn new(max_input_line_length: usize) -> Self {
+        Self {
+            lines: LinesCodec::new_with_max_length(max_input_line_length),
+            mode: ProtocolMode::Ascii,
+        }
+    }
+
+    /// Switches the protocol used for all following client input
+    pub fn set_mode(&mut self, mode: ProtocolMode) {
+        self.mode = mode;
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = ClientFrame;
+    type Error = ClientCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.mode {
+            ProtocolMode::Ascii => Ok(self.lines.decode(src)?.map(ClientFrame::Line)),
+            ProtocolMode::Binary => decode_binary(src),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.mode {
+            ProtocolMode::Ascii => Ok(self.lines.decode_eof(src)?.map(ClientFrame::Line)),
+            // An incomplete record at the end of the stream is dropped
+            ProtocolMode::Binary => decode_binary(src),
+        }
+    }
+}
+
+fn decode_binary(src: &mut BytesMut) -> Result<Option<ClientFrame>, ClientCodecError> {
+    let Some(&opcode) = src.first() else {
+        return Ok(None);
+    };
+
+    let request = match opcode {
+        OPCODE_SET_PIXEL => {
+            if src.len() < SET_PIXEL_LEN {
+                src.reserve(SET_PIXEL_LEN - src.len());
+                return Ok(None);
+            }
+
+            src.advance(1);
+            let x = src.get_u16();
+            let y = src.get_u16();
+            let rgba =
+                (src.get_u8() as u32) << 16 | (src.get_u8() as u32) << 8 | src.get_u8() as u32;
+            Request::SetPixel {
+                x,
+                y,
+                rgba,
+                alpha: u8::MAX,
+            }
+        }
+        OPCODE_BLEND_PIXEL => {
+            if src.len() < BLEND_PIXEL_LEN {
+                src.reserve(BLEND_PIXEL_LEN - src.len());
+                return Ok(None);
+            }
+
+            src.advance(1);
+            let x = src.get_u16();
+            let y = src.get_u16();
+            let rgba =
+                (src.get_u8() as u32) << 16 | (src.get_u8() as u32) << 8 | src.get_u8() as u32;
+            let alpha = src.get_u8();
+            Request::SetPixel { x, y, rgba, alpha }
+        }
+        OPCODE_DONE => {
+            src.advance(1);
+            Request::Done
+        }
+        opcode => return Err(ClientCodecError::UnknownOpcode(opcode)),
+    };
+
+    Ok(Some(ClientFrame::Request(request)))
+}
+
+impl<T: AsRef<str>> Encoder<T> for ClientCodec {
+    type Error = ClientCodecError;
+
+    fn encode(&mut self, line: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        Ok(self.lines.encode(line, dst)?)
+    }
+}
+
+impl From<LinesCodecError> for ClientCodecError {
+    fn from(err: LinesCodecError) -> Self {
+        Self::Lines(err)
+    }
+}
+
+impl From<io::Error> for ClientCodecError {
+    fn from(err: io::Error) -> Self {
+        Self::Lines(LinesCodecError::Io(err))
+    }
+}
+
+impl fmt::Display for ClientCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lines(err) => err.fmt(f),
+            Self::UnknownOpcode(opcode) => write!(f, "unknown binary opcode {opcode:#04x}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientCodecError {}
@@ -0,0 +1,240 @@
+/// State of a [`super::client_connection::ClientConnection`] regarding login and slots.
+///
+/// ```text
+/// Connected --LOGIN--> LoggedIn --slot start--> Painting --DONE--> Finished --slot end--> Waiting
+///                                                   |                                      |  ^
+///                                                   +--slot end (no grace period)----------+  |
+///                                                   |                                      |  |
+///                                                   +--slot end--> GracePeriod --DONE------+  |
+///                                                                       |                     |
+///                                                                       +--grace period over--+
+/// Waiting --slot start--> Painting
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Not logged in yet
+    Connected,
+
+    /// Logged in and waiting for the first slot
+    LoggedIn,
+
+    /// Waiting for the next slot
+    Waiting,
+
+    /// The slot is running, pixels are accepted until `DONE`
+    Painting,
+
+    /// The slot ended without a `DONE`, which is still accepted for a short time. Further pixels are not, as they would
+    /// lengthen the slot.
+    GracePeriod,
+
+    /// `DONE` was sent, waiting for the end of the slot
+    Finished,
+}
+
+/// Everything that can change the [`ConnectionState`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Successful `LOGIN`
+    Login,
+    SwitchProtocol,
+    SetPixel,
+    Done,
+    SlotStart,
+    SlotEnd {
+        /// Whether a late `DONE` is accepted after the end of the slot
+        grace_period: bool,
+    },
+    GracePeriodOver,
+}
+
+/// An [`Event`] that is not allowed in the current [`ConnectionState`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IllegalTransition {
+    LoginNeeded,
+    AlreadyLoggedIn,
+
+    /// Pixels can only be set during the own slot
+    NotYourSlot,
+
+    /// Pixels can not be set after `DONE` was sent in the same slot
+    PixelAfterDone,
+    DoneOutsideSlot,
+    AlreadyDone,
+
+    /// The scheduler sent a slot event that does not fit the state. This is an error of the server, not of the client.
+    UnexpectedSlotEvent,
+}
+
+impl ConnectionState {
+    /// Returns the state after the given event, or why the event is not allowed in the current state
+    pub fn on(self, event: Event) -> Result<Self, IllegalTransition> {
+        use ConnectionState::*;
+
+        match (self, event) {
+            (Connected, Event::Login) => Ok(LoggedIn),
+            (_, Event::Login) => Err(IllegalTransition::AlreadyLoggedIn),
+            (Connected, Event::SwitchProtocol | Event::SetPixel | Event::Done) => {
+                Err(IllegalTransition::LoginNeeded)
+            }
+
+            (state, Event::SwitchProtocol) => Ok(state),
+
+            (Painting, Event::SetPixel) => Ok(self),
+            (LoggedIn | Waiting | GracePeriod, Event::SetPixel) => {
+                Err(IllegalTransition::NotYourSlot)
+            }
+            (Finished, Event::SetPixel) => Err(IllegalTransition::PixelAfterDone),
+
+            (Painting, Event::Done) => Ok(Finished),
+            // The slot is already over, so there is nothing left to wait for
+            (GracePeriod, Event::Done) => Ok(Waiting),
+            (LoggedIn | Waiting, Event::Done) => Err(IllegalTransition::DoneOutsideSlot),
+            (Finished, Event::Done) => Err(IllegalTransition::AlreadyDone),
+
+            (LoggedIn | Waiting, Event::SlotStart) => Ok(Painting),
+            (Painting, Event::SlotEnd { grace_period: true }) => Ok(GracePeriod),
+            (
+                Painting,
+                Event::SlotEnd {
+                    grace_period: false,
+                },
+            )
+            | (Finished, Event::SlotEnd { .. }) => Ok(Waiting),
+            (GracePeriod, Event::GracePeriodOver) => Ok(Waiting),
+            (_, Event::SlotStart | Event::SlotEnd { .. } | Event::GracePeriodOver) => {
+                Err(IllegalTransition::UnexpectedSlotEvent)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionState::*, Event, IllegalTransition};
+
+    const SLOT_END: Event = Event::SlotEnd {
+        grace_period: false,
+    };
+    const SLOT_END_WITH_GRACE_PERIOD: Event = Event::SlotEnd { grace_period: true };
+
+    #[test]
+    fn regular_slots() {
+        let mut state = Connected;
+        for (event, expected) in [
+            (Event::Login, LoggedIn),
+            (Event::SwitchProtocol, LoggedIn),
+            (Event::SlotStart, Painting),
+            (Event::SetPixel, Painting),
+            (Event::SetPixel, Painting),
+            (Event::Done, Finished),
+            (SLOT_END_WITH_GRACE_PERIOD, Waiting),
+            (Event::SlotStart, Painting),
+            (Event::Done, Finished),
+            (SLOT_END, Waiting),
+        ] {
+            state = state.on(event).unwrap();
+            assert_eq!(state, expected, "state after {event:?}");
+        }
+    }
+
+    #[test]
+    fn login_needed() {
+        for event in [Event::SwitchProtocol, Event::SetPixel, Event::Done] {
+            assert_eq!(
+                Connected.on(event),
+                Err(IllegalTransition::LoginNeeded),
+                "{event:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn login_only_once() {
+        for state in [LoggedIn, Waiting, Painting, GracePeriod, Finished] {
+            assert_eq!(
+                state.on(Event::Login),
+                Err(IllegalTransition::AlreadyLoggedIn),
+                "{state:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn pixels_only_during_own_slot() {
+        assert_eq!(
+            LoggedIn.on(Event::SetPixel),
+            Err(IllegalTransition::NotYourSlot)
+        );
+        assert_eq!(
+            Waiting.on(Event::SetPixel),
+            Err(IllegalTransition::NotYourSlot)
+        );
+        assert_eq!(
+            Finished.on(Event::SetPixel),
+            Err(IllegalTransition::PixelAfterDone)
+        );
+    }
+
+    #[test]
+    fn done_only_once_per_slot() {
+        assert_eq!(
+            LoggedIn.on(Event::Done),
+            Err(IllegalTransition::DoneOutsideSlot)
+        );
+        assert_eq!(
+            Waiting.on(Event::Done),
+            Err(IllegalTransition::DoneOutsideSlot)
+        );
+        assert_eq!(
+            Finished.on(Event::Done),
+            Err(IllegalTransition::AlreadyDone)
+        );
+    }
+
+    #[test]
+    fn slot_not_closed_in_time() {
+        assert_eq!(Painting.on(SLOT_END), Ok(Waiting));
+    }
+
+    #[test]
+    fn grace_period() {
+        let state = Painting.on(SLOT_END_WITH_GRACE_PERIOD).unwrap();
+        assert_eq!(state, GracePeriod);
+        assert_eq!(state.on(Event::Done), Ok(Waiting));
+        assert_eq!(state.on(Event::GracePeriodOver), Ok(Waiting));
+    }
+
+    #[test]
+    fn no_pixels_during_grace_period() {
+        assert_eq!(
+            GracePeriod.on(Event::SetPixel),
+            Err(IllegalTransition::NotYourSlot)
+        );
+    }
+
+    #[test]
+    fn unexpected_slot_events() {
+        for state in [Connected, Painting, GracePeriod, Finished] {
+            assert_eq!(
+                state.on(Event::SlotStart),
+                Err(IllegalTransition::UnexpectedSlotEvent),
+                "{state:?}"
+            );
+        }
+        for state in [Connected, LoggedIn, Waiting, GracePeriod] {
+            assert_eq!(
+                state.on(SLOT_END),
+                Err(IllegalTransition::UnexpectedSlotEvent),
+                "{state:?}"
+            );
+        }
+        for state in [Connected, LoggedIn, Waiting, Painting, Finished] {
+            assert_eq!(
+                state.on(Event::GracePeriodOver),
+                Err(IllegalTransition::UnexpectedSlotEvent),
+                "{state:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt::{self, Display};
+
+/// Stable, machine-readable code of an `ERROR` response. See PROTOCOL.md for the list of codes.
+///
+/// Errors are sent as `ERROR <code> [<parameter>...] <message>`. The number of parameters is fixed per code, whereas
+/// the human readable message can change at any time and should not be parsed by clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    LineTooLong,
+    UnknownOpcode,
+    UnknownCommand,
+    InvalidArguments,
+    UnsupportedVersion,
+    LoginNeeded,
+    LoginFailed,
+    AlreadyLoggedIn,
+    AlreadyConnected,
+    LoginReplaced,
+    Banned,
+    ConnectionLimit,
+    Kicked,
+    NotYourSlot,
+    PixelAfterDone,
+    DoneOutsideSlot,
+    AlreadyDone,
+    Quota,
+    OutOfBounds,
+    Protected,
+    SlotNotClosed,
+    Penalized,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::LineTooLong => "E_LINE_TOO_LONG",
+            ErrorCode::UnknownOpcode => "E_UNKNOWN_OPCODE",
+            ErrorCode::UnknownCommand => "E_UNKNOWN_COMMAND",
+            ErrorCode::InvalidArguments => "E_INVALID_ARGUMENTS",
+            ErrorCode::UnsupportedVersion => "E_UNSUPPORTED_VERSION",
+            ErrorCode::LoginNeeded => "E_LOGIN_NEEDED",
+            ErrorCode::LoginFailed => "E_LOGIN_FAILED",
+            ErrorCode::AlreadyLoggedIn => "E_ALREADY_LOGGED_IN",
+            ErrorCode::AlreadyConnected => "E_ALREADY_CONNECTED",
+            ErrorCode::LoginReplaced => "E_LOGIN_REPLACED",
+            ErrorCode::Banned => "E_BANNED",
+            ErrorCode::ConnectionLimit => "E_CONNECTION_LIMIT",
+            ErrorCode::Kicked => "E_KICKED",
+            ErrorCode::NotYourSlot => "E_NOT_YOUR_SLOT",
+            ErrorCode::PixelAfterDone => "E_PIXEL_AFTER_DONE",
+            ErrorCode::DoneOutsideSlot => "E_DONE_OUTSIDE_SLOT",
+            ErrorCode::AlreadyDone => "E_ALREADY_DONE",
+            ErrorCode::Quota => "E_QUOTA",
+            ErrorCode::OutOfBounds => "E_OUT_OF_BOUNDS",
+            ErrorCode::Protected => "E_PROTECTED",
+            ErrorCode::SlotNotClosed => "E_SLOT_NOT_CLOSED",
+            ErrorCode::Penalized => "E_PENALIZED",
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    const ALL: [ErrorCode; 22] = [
+        ErrorCode::LineTooLong,
+        ErrorCode::UnknownOpcode,
+        ErrorCode::UnknownCommand,
+        ErrorCode::InvalidArguments,
+        ErrorCode::UnsupportedVersion,
+        ErrorCode::LoginNeeded,
+        ErrorCode::LoginFailed,
+        ErrorCode::AlreadyLoggedIn,
+        ErrorCode::AlreadyConnected,
+        ErrorCode::LoginReplaced,
+        ErrorCode::Banned,
+        ErrorCode::ConnectionLimit,
+        ErrorCode::Kicked,
+        ErrorCode::NotYourSlot,
+        ErrorCode::PixelAfterDone,
+        ErrorCode::DoneOutsideSlot,
+        ErrorCode::AlreadyDone,
+        ErrorCode::Quota,
+        ErrorCode::OutOfBounds,
+        ErrorCode::Protected,
+        ErrorCode::SlotNotClosed,
+        ErrorCode::Penalized,
+    ];
+
+    #[test]
+    fn codes_are_single_words() {
+        assert_eq!(ErrorCode::NotYourSlot.to_string(), "E_NOT_YOUR_SLOT");
+        assert_eq!(
+            format!("ERROR {} Login needed", ErrorCode::LoginNeeded),
+            "ERROR E_LOGIN_NEEDED Login needed"
+        );
+
+        for code in ALL {
+            let formatted = code.to_string();
+            assert_eq!(formatted, code.as_str());
+            assert!(formatted.starts_with("E_"), "{formatted}");
+            assert!(
+                formatted
+                    .bytes()
+                    .all(|b| b.is_ascii_uppercase() || b == b'_'),
+                "{formatted}"
+            );
+        }
+    }
+
+    #[test]
+    fn codes_are_unique_and_documented() {
+        let protocol = include_str!("../../PROTOCOL.md");
+        let mut seen = HashSet::new();
+        for code in ALL {
+            assert!(seen.insert(code.as_str()), "{code} is used twice");
+            assert!(
+                protocol.contains(&format!("`{code}`")),
+                "{code} is not documented in PROTOCOL.md"
+            );
+        }
+    }
+}
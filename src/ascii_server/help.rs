@@ -0,0 +1,145 @@
+//! Responses to `HELP`, generated from [`COMMANDS`] and the config
+
+use std::fmt::Write;
+
+use humantime::format_duration;
+
+use super::parser::{Command, COMMANDS};
+use crate::config::Config;
+
+/// Lists all commands and the limits of the server
+pub fn overview(config: &Config) -> String {
+    let mut lines =
+        vec!["Pixelstrom, paint on a shared canvas together with others. Commands:".to_owned()];
+    for command in &COMMANDS {
+        lines.push(format!(
+            "  {} - {}",
+            command.usages.join(" | "),
+            command.summary
+        ));
+    }
+
+    let canvas = &config.canvas;
+    let scheduler = &config.scheduler;
+    let ascii_server = &config.ascii_server;
+    lines.push(format!("Canvas: {}x{} pixels", canvas.width, canvas.height));
+    let adaptive = &scheduler.adaptive;
+    lines.push(if adaptive.enabled {
+        format!(
+            "Slots: {} to {} with {} to {} pixels, depending on the number of users",
+            format_duration(adaptive.min_slot_duration),
+            format_duration(adaptive.max_slot_duration),
+            adaptive.min_pixels_per_slot,
+            adaptive.max_pixels_per_slot
+        )
+    } else {
+        format!(
+            "Slots: {} with up to {} pixels",
+            format_duration(scheduler.slot_duration),
+            scheduler.max_pixels_per_slot
+        )
+    });
+    lines.push(format!(
+        "Connections: up to {} per IP address, lines of up to {} characters",
+        ascii_server.max_connections_per_ip, ascii_server.max_input_line_length
+    ));
+    lines.push("Send \"HELP <command>\" for the details of a command".to_owned());
+
+    prefix_lines(&lines)
+}
+
+/// Explains a single command
+pub fn details(command: &Command) -> String {
+    let mut lines: Vec<String> = command
+        .usages
+        .iter()
+        .map(|usage| usage.to_string())
+        .collect();
+    lines.push(command.details.to_owned());
+
+    prefix_lines(&lines)
+}
+
+/// Prefixes every line with `HELP `, so that clients can tell the help apart from other responses
+fn prefix_lines(lines: &[String]) -> String {
+    let mut help = String::new();
+    for (index, line) in lines.iter().enumerate() {
+        if index > 0 {
+            help.push('\n');
+        }
+        write!(help, "HELP {line}").expect("writing to a String can not fail");
+    }
+
+    help
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::ascii_server::parser::find_command;
+
+    fn assert_prefixed(help: &str) {
+        for line in help.lines() {
+            assert!(line.starts_with("HELP "), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn overview_lists_all_commands() {
+        let overview = overview(&Config::default());
+        assert_prefixed(&overview);
+
+        for command in &COMMANDS {
+            assert!(
+                overview.contains(&format!(
+                    "HELP   {} - {}\n",
+                    command.usages.join(" | "),
+                    command.summary
+                )),
+                "{} is missing",
+                command.name
+            );
+        }
+        assert!(overview.ends_with("HELP Send \"HELP <command>\" for the details of a command"));
+    }
+
+    #[test]
+    fn overview_shows_the_limits() {
+        let mut config = Config::default();
+        config.canvas.width = 640;
+        config.canvas.height = 480;
+        config.scheduler.max_pixels_per_slot = 123;
+        config.scheduler.slot_duration = Duration::from_millis(1500);
+        let overview = overview(&config);
+        assert!(overview.contains("HELP Canvas: 640x480 pixels\n"));
+        assert!(overview.contains("HELP Slots: 1s 500ms with up to 123 pixels\n"));
+
+        config.scheduler.adaptive.enabled = true;
+        config.scheduler.adaptive.min_slot_duration = Duration::from_millis(100);
+        config.scheduler.adaptive.max_slot_duration = Duration::from_secs(2);
+        config.scheduler.adaptive.min_pixels_per_slot = 10;
+        config.scheduler.adaptive.max_pixels_per_slot = 200;
+        assert!(super::overview(&config).contains(
+            "HELP Slots: 100ms to 2s with 10 to 200 pixels, depending on the number of users\n"
+        ));
+    }
+
+    #[test]
+    fn details_of_every_command() {
+        for command in &COMMANDS {
+            let found = find_command(command.name).expect("command can be found by its name");
+            assert_eq!(found.summary, command.summary);
+
+            let details = details(command);
+            assert_prefixed(&details);
+            let lines: Vec<_> = details.lines().collect();
+            assert_eq!(lines.len(), command.usages.len() + 1, "{details}");
+            for (line, usage) in lines.iter().zip(command.usages) {
+                assert_eq!(*line, format!("HELP {usage}"));
+                assert!(usage.starts_with(command.name), "{usage}");
+            }
+        }
+    }
+}
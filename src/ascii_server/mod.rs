@@ -2,11 +2,11 @@ use std::{
     collections::{hash_map::Entry, HashMap},
     net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::Duration,
 };
 
 use anyhow::Context;
 use client_connection::ClientConnection;
+use error_code::ErrorCode;
 use tokio::{
     io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
@@ -14,18 +14,23 @@ use tokio::{
 };
 use tracing::{debug, info, warn};
 use user_scheduler::UserScheduler;
+use violation_tracker::{Offender, ViolationTracker};
 
-use crate::{app_state::AppState, ascii_server::user_manager::UserManager};
+use crate::{
+    app_state::AppState, ascii_server::user_manager::UserManager, config::Config, metrics,
+};
 
 mod client_connection;
+mod codec;
+mod connection_state;
+mod error_code;
+mod help;
 mod parser;
+pub mod scheduling_policy;
+mod shape;
 mod user_manager;
-mod user_scheduler;
-
-const MAX_INPUT_LINE_LENGTH: usize = 128;
-const MAX_CONNECTIONS_PER_IP: usize = 10;
-
-const HELP_TEXT: &str = "Help text here :)";
+pub mod user_scheduler;
+pub mod violation_tracker;
 
 pub struct AsciiServer<'a> {
     listener: TcpListener,
@@ -33,31 +38,22 @@ pub struct AsciiServer<'a> {
     shared_state: Arc<AppState>,
     user_manager: UserManager,
     user_scheduler: Arc<UserScheduler>,
+    violation_tracker: Arc<ViolationTracker>,
     connections_per_ip: Arc<RwLock<HashMap<IpAddr, usize>>>,
 
     _client_connections: HashMap<&'a str, ClientConnection<'a>>,
 
-    max_pixels_per_slot: usize,
-    slot_duration: Duration,
-
-    width: u16,
-    height: u16,
+    config: Config,
 }
 
 impl AsciiServer<'_> {
-    pub async fn new(
-        shared_state: Arc<AppState>,
-        listener_address: &str,
-        max_pixels_per_slot: usize,
-        slot_duration: Duration,
-        width: u16,
-        height: u16,
-    ) -> anyhow::Result<Self> {
+    pub async fn new(shared_state: Arc<AppState>, config: &Config) -> anyhow::Result<Self> {
+        let listener_address = config.ascii_server.listener_address;
         let listener = TcpListener::bind(listener_address).await.with_context(|| {
             format!("Failed to bind to ASCII listener address {listener_address}")
         })?;
 
-        let user_scheduler = Arc::new(UserScheduler::new(shared_state.clone(), slot_duration));
+        let user_scheduler = Arc::new(UserScheduler::new(shared_state.clone(), &config.scheduler));
 
         let user_scheduler_clone = user_scheduler.clone();
         tokio::spawn(async move {
@@ -67,20 +63,30 @@ impl AsciiServer<'_> {
 
         Ok(Self {
             shared_state,
-            user_manager: UserManager::new_from_save_file()
+            user_manager: UserManager::new_from_save_file(&config.ascii_server.users_file)
                 .await
                 .context("Failed to create user manager")?,
             user_scheduler,
+            violation_tracker: Arc::new(ViolationTracker::new(&config.violations)),
             connections_per_ip: Default::default(),
             _client_connections: Default::default(),
             listener,
-            max_pixels_per_slot,
-            slot_duration,
-            width,
-            height,
+            config: config.clone(),
         })
     }
 
+    pub fn user_scheduler(&self) -> Arc<UserScheduler> {
+        self.user_scheduler.clone()
+    }
+
+    pub fn violation_tracker(&self) -> Arc<ViolationTracker> {
+        self.violation_tracker.clone()
+    }
+
+    pub fn connections_per_ip(&self) -> Arc<RwLock<HashMap<IpAddr, usize>>> {
+        self.connections_per_ip.clone()
+    }
+
     async fn handle_connection(
         &self,
         mut socket: TcpStream,
@@ -92,6 +98,29 @@ impl AsciiServer<'_> {
 
         debug!(%peer_ip, %peer_addr, "Got new connection");
 
+        if let Some(ban) = self
+            .violation_tracker
+            .active_ban(&Offender::Ip(peer_ip))
+            .await
+        {
+            debug!(%peer_ip, %peer_addr, "Rejecting connection from banned IP address");
+            socket
+                .write_all(
+                    format!(
+                        "ERROR {} This IP address is banned {ban}\n",
+                        ErrorCode::Banned
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .context("Failed to send response to client")?;
+            socket
+                .shutdown()
+                .await
+                .context("Failed to shutdown socket")?;
+            return Ok(());
+        }
+
         if !self
             .check_and_increment_connection_limit(peer_ip, &mut socket)
             .await
@@ -103,24 +132,28 @@ impl AsciiServer<'_> {
         let mut client_connection = ClientConnection::new(
             &self.user_manager,
             &self.user_scheduler,
+            &self.violation_tracker,
             &self.shared_state,
-            self.max_pixels_per_slot,
-            self.slot_duration,
-            self.width,
-            self.height,
+            &self.config,
+            peer_ip,
         );
-        debug!(%peer_ip, %peer_addr, "Closing connection");
-        client_connection
+        let result = client_connection
             .run(&mut socket)
             .await
-            .context("Failed to run client connection")?;
+            .context("Failed to run client connection");
+
+        // Clean up regardless of how the connection ended, so that closed connections don't occupy slots or count
+        // against the connection limit
+        debug!(%peer_ip, %peer_addr, "Closing connection");
+        client_connection.unregister().await;
+        self.dec_connections(peer_ip).await;
+        result?;
 
         socket
             .shutdown()
             .await
             .context("Failed to shut down connection")?;
 
-        self.dec_connections(peer_ip).await;
         debug!(%peer_ip, %peer_addr, "Connection closed");
 
         Ok(())
@@ -132,13 +165,15 @@ impl AsciiServer<'_> {
         peer_ip: IpAddr,
         socket: &mut TcpStream,
     ) -> anyhow::Result<bool> {
+        let max_connections_per_ip = self.config.ascii_server.max_connections_per_ip;
         let mut connections_per_ip = self.connections_per_ip.write().await;
         let connections = connections_per_ip.entry(peer_ip).or_default();
-        if *connections >= MAX_CONNECTIONS_PER_IP {
+        if *connections >= max_connections_per_ip {
             socket
                 .write_all(
                     format!(
-                        "ERROR Connection limit of {MAX_CONNECTIONS_PER_IP} connections per IP reached\n"
+                        "ERROR {} {max_connections_per_ip} Connection limit of {max_connections_per_ip} connections per IP reached\n",
+                        ErrorCode::ConnectionLimit
                     )
                     .as_bytes(),
                 )
@@ -153,10 +188,12 @@ impl AsciiServer<'_> {
         }
 
         *connections += 1;
+        metrics::ASCII_CONNECTIONS.inc();
         Ok(true)
     }
 
     async fn dec_connections(&self, ip: IpAddr) {
+        metrics::ASCII_CONNECTIONS.dec();
         let mut connections_per_ip = self.connections_per_ip.write().await;
         let connections = connections_per_ip.entry(ip);
         match connections {
@@ -178,7 +215,11 @@ impl AsciiServer<'_> {
 
 impl AsciiServer<'static> {
     pub async fn run(self) -> anyhow::Result<()> {
-        info!("Started ASCII server at localhost:1234");
+        let local_addr = self
+            .listener
+            .local_addr()
+            .context("Failed to get local address of ASCII listener")?;
+        info!(%local_addr, "Started ASCII server");
         let server = Arc::new(self);
 
         loop {
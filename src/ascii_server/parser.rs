@@ -5,16 +5,31 @@ use nom::{
     branch::alt,
     bytes::complete::{tag, take_while_m_n},
     character::complete::{alphanumeric1, char},
-    combinator::{map, map_res},
+    combinator::{map, map_res, opt, verify},
+    error::ErrorKind,
+    multi::count,
     sequence::{preceded, separated_pair},
     IResult, Parser,
 };
 
+use super::{
+    codec::ProtocolMode,
+    shape::Shape,
+    violation_tracker::{Ban, Sentence},
+};
+
 // FIXME: This potentially leaks the password from the `Login` request.
 // Use something like educe or derive-more to skip this field
 #[derive(Debug)]
 pub enum Request<'a> {
-    Help,
+    Hello {
+        /// Highest protocol version supported by the client
+        version: Option<u32>,
+    },
+    Help {
+        /// Command to show the details of, all commands are listed in case none is given
+        command: Option<&'a str>,
+    },
     Size,
     Login {
         username: &'a str,
@@ -28,14 +43,42 @@ pub enum Request<'a> {
         x: u16,
         y: u16,
         rgba: u32,
+        /// Opacity of the pixel, `255` (fully opaque) in case no alpha was given
+        alpha: u8,
+    },
+    /// A rectangle or line filled with a single color
+    SetShape {
+        shape: Shape,
+        rgba: u32,
+        alpha: u8,
+    },
+    /// Consecutive pixels in a row, each with its own color
+    SetSpan {
+        x: u16,
+        y: u16,
+        /// Colors as `(rgba, alpha)`, one per pixel
+        colors: Vec<(u32, u8)>,
     },
     Done,
+    Protocol {
+        mode: ProtocolMode,
+    },
 }
 
 #[derive(Debug)]
-
 pub enum Response {
-    Help,
+    Hello {
+        version: u32,
+    },
+    UnsupportedVersion {
+        version: u32,
+    },
+    Help {
+        command: Option<&'static Command>,
+    },
+    UnknownCommand {
+        command: String,
+    },
     Size {
         width: u16,
         height: u16,
@@ -44,6 +87,12 @@ pub enum Response {
     LoginSucceeded,
     LoginFailed,
     AlreadyLoggedIn,
+    AlreadyConnected,
+    LoginReplaced,
+    Banned {
+        ban: Ban,
+    },
+    Kicked,
     GetPixel {
         x: u16,
         y: u16,
@@ -52,44 +101,241 @@ pub enum Response {
     Start {
         max_pixels_per_slot: usize,
         slot_duration: Duration,
+
+        /// Unix timestamp in milliseconds at which the slot ends
+        deadline_unix_ms: u64,
     },
     Done {
         num_pixels: usize,
+
+        /// Time between the start of the slot and the `DONE`
+        elapsed: Duration,
+
+        /// The `DONE` was received during the grace period after the slot ended
+        late: bool,
+    },
+    NotYourSlot {
+        sentence: Sentence,
     },
-    NotYourSlot,
+    PixelAfterDone {
+        sentence: Sentence,
+    },
+    DoneOutsideSlot,
+    AlreadyDone,
     QuotaExceeded {
         max_pixels_per_slot: usize,
+        sentence: Sentence,
+    },
+    PixelOutOfBounds {
+        x: u32,
+        y: u32,
+        width: u16,
+        height: u16,
+    },
+    PixelProtected {
+        x: u16,
+        y: u16,
+        region: String,
+    },
+    ProtocolSwitched {
+        mode: ProtocolMode,
     },
     SlotNotClosedInTime {
         slot_duration: Duration,
+        grace_period: Duration,
+        sentence: Sentence,
     },
+
+    /// Pixels and `DONE` of a client that was penalized in the current slot, which are ignored until the next slot
+    Penalized,
 }
 
-pub fn parse_request(i: &str) -> IResult<&str, Request> {
-    // Trying to sort descending by number of occurrences for performance reasons
-    alt((
-        parse_get_or_set_pixel,
-        parse_done,
-        parse_size,
-        parse_login,
-        parse_help,
-    ))
-    .parse(i)
+/// Version of the protocol, which is increased on incompatible changes only. Compatible additions are announced as
+/// [`CAPABILITIES`] instead.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version that is still supported
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features of the protocol, announced in the response to `HELLO`
+pub const CAPABILITIES: [&str; 8] = [
+    "ALPHA",
+    "BINARY",
+    "ERROR_CODES",
+    "GRACE_PERIOD",
+    "HELP",
+    "RECT",
+    "LINE",
+    "SPAN",
+];
+
+/// A request of the ASCII protocol.
+///
+/// [`parse_request`] dispatches on the name of the command and `HELP` is generated from these definitions, so the
+/// documentation of a command can not get out of sync with its parser.
+#[derive(Debug)]
+pub struct Command {
+    pub name: &'static str,
+
+    /// Syntax of the request, one entry per variant
+    pub usages: &'static [&'static str],
+
+    /// One line summary, shown in the list of all commands
+    pub summary: &'static str,
+
+    /// Shown by `HELP <command>`
+    pub details: &'static str,
+
+    parse: fn(&str) -> IResult<&str, Request<'_>>,
+}
+
+/// All commands, sorted descending by number of occurrences for performance reasons
+pub const COMMANDS: [Command; 10] = [
+    Command {
+        name: "PX",
+        usages: &["PX <x> <y>", "PX <x> <y> <rrggbb[aa]>"],
+        summary: "Get or set the color of a pixel",
+        details: "Getting a pixel is answered with \"PX <x> <y> <rrggbb>\". Pixels can only be set during your slot and \
+            are painted all at once when you send \"DONE\". The optional alpha blends the pixel onto the canvas.",
+        parse: parse_get_or_set_pixel,
+    },
+    Command {
+        name: "DONE",
+        usages: &["DONE"],
+        summary: "Finish your slot and paint your pixels",
+        details: "Needs to be sent before your slot ends, keep the network delay in mind. Answered with \
+            \"DONE <pixels> <elapsed ms>\", followed by \" LATE\" in case it arrived during the grace period after the \
+            end of the slot.",
+        parse: parse_done,
+    },
+    Command {
+        name: "RECT",
+        usages: &["RECT <x> <y> <width> <height> <rrggbb[aa]>"],
+        summary: "Fill a rectangle",
+        details: "<x> <y> is the top left corner. Every pixel of the rectangle counts against your quota and the \
+            rectangle is rejected as a whole in case it is not within the canvas or any of its pixels is protected.",
+        parse: parse_rect,
+    },
+    Command {
+        name: "SPAN",
+        usages: &["SPAN <x> <y> <length> <rrggbb[aa]>..."],
+        summary: "Set <length> pixels in a row, starting at <x> <y>, each with its own color",
+        details: "Needs exactly <length> colors. Every pixel counts against your quota and the span is rejected as a \
+            whole in case it is not within the canvas or any of its pixels is protected. The span needs to fit into a \
+            single line (see the line length in \"HELP\"), every color takes 7 (rrggbb) or 9 (rrggbbaa) characters \
+            including the space, so longer rows need to be split into multiple spans.",
+        parse: parse_span,
+    },
+    Command {
+        name: "LINE",
+        usages: &["LINE <x1> <y1> <x2> <y2> <rrggbb[aa]>"],
+        summary: "Draw a line between two pixels",
+        details: "Both ends are part of the line. Every pixel of the line counts against your quota and the line is \
+            rejected as a whole in case any of its pixels is protected.",
+        parse: parse_line,
+    },
+    Command {
+        name: "SIZE",
+        usages: &["SIZE"],
+        summary: "Get the size of the canvas",
+        details: "Answered with \"SIZE <width> <height>\".",
+        parse: parse_size,
+    },
+    Command {
+        name: "LOGIN",
+        usages: &["LOGIN <username> <password>"],
+        summary: "Log in, the first login of a username registers it",
+        details: "Username and password need to be alphanumeric. Answered with \"LOGIN SUCCEEDED\", afterwards you \
+            get \"START <max pixels> <slot duration ms> <deadline unix ms>\" at the beginning of each of your slots.",
+        parse: parse_login,
+    },
+    Command {
+        name: "PROTOCOL",
+        usages: &["PROTOCOL ASCII", "PROTOCOL BINARY"],
+        summary: "Switch to the binary protocol, which can not be undone",
+        details: "In the binary protocol only pixels and \"DONE\" can be sent: 0x01 x y r g b sets a pixel, \
+            0x03 x y r g b a blends a pixel and 0x02 finishes the slot. x and y are u16 big endian, all other values \
+            single bytes. Responses are always sent as ASCII lines. There is no way back to the ASCII protocol, \
+            \"PROTOCOL ASCII\" is only accepted while still using it. Sent in the binary protocol it is the unknown \
+            opcode 0x50 and closes the connection.",
+        parse: parse_protocol,
+    },
+    Command {
+        name: "HELLO",
+        usages: &["HELLO", "HELLO <protocol version>"],
+        summary: "Get the protocol version and the capabilities of the server",
+        details: "Answered with \"HELLO <protocol version> <capability>...\". Send the highest protocol version your \
+            client supports and the server answers with the highest version both of you support. Clients should \
+            ignore capabilities they don't know.",
+        parse: parse_hello,
+    },
+    Command {
+        name: "HELP",
+        usages: &["HELP", "HELP <command>"],
+        summary: "List all commands or show the details of a command",
+        details: "Every line of the response starts with \"HELP \".",
+        parse: parse_help,
+    },
+];
+
+/// Why a line could not be parsed, see [`explain_invalid_request`]
+#[derive(Debug)]
+pub enum InvalidRequest<'a> {
+    UnknownCommand { command: &'a str },
+    InvalidArguments { command: &'static Command },
+}
+
+/// Returns the command with the given name
+pub fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+fn command_name(line: &str) -> &str {
+    line.split(' ').next().unwrap_or_default()
+}
+
+/// Explains why the given line was rejected by [`parse_request`], so that the client gets a concise error instead of
+/// the internals of the parser
+pub fn explain_invalid_request(line: &str) -> InvalidRequest<'_> {
+    let name = command_name(line);
+    match find_command(name) {
+        Some(command) => InvalidRequest::InvalidArguments { command },
+        None => InvalidRequest::UnknownCommand { command: name },
+    }
+}
+
+pub fn parse_request(i: &str) -> IResult<&str, Request<'_>> {
+    match find_command(command_name(i)) {
+        Some(command) => (command.parse)(i),
+        None => Err(nom::Err::Error(nom::error::Error::new(i, ErrorKind::Tag))),
+    }
+}
+
+fn parse_hello(i: &str) -> IResult<&str, Request<'_>> {
+    let (i, version) = preceded(
+        tag("HELLO"),
+        opt(preceded(char(' '), nom::character::complete::u32)),
+    )
+    .parse(i)?;
+
+    Ok((i, Request::Hello { version }))
 }
 
-fn parse_help(i: &str) -> IResult<&str, Request> {
-    map(tag("HELP"), |_| Request::Help).parse(i)
+fn parse_help(i: &str) -> IResult<&str, Request<'_>> {
+    let (i, command) = preceded(tag("HELP"), opt(preceded(char(' '), alphanumeric1))).parse(i)?;
+
+    Ok((i, Request::Help { command }))
 }
 
-fn parse_size(i: &str) -> IResult<&str, Request> {
+fn parse_size(i: &str) -> IResult<&str, Request<'_>> {
     map(tag("SIZE"), |_| Request::Size).parse(i)
 }
 
-fn parse_done(i: &str) -> IResult<&str, Request> {
+fn parse_done(i: &str) -> IResult<&str, Request<'_>> {
     map(tag("DONE"), |_| Request::Done).parse(i)
 }
 
-fn parse_login(i: &str) -> IResult<&str, Request> {
+fn parse_login(i: &str) -> IResult<&str, Request<'_>> {
     let (i, (username, password)) = preceded(
         tag("LOGIN "),
         separated_pair(alphanumeric1, char(' '), alphanumeric1),
@@ -99,7 +345,20 @@ fn parse_login(i: &str) -> IResult<&str, Request> {
     Ok((i, Request::Login { username, password }))
 }
 
-fn parse_get_or_set_pixel(i: &str) -> IResult<&str, Request> {
+fn parse_protocol(i: &str) -> IResult<&str, Request<'_>> {
+    let (i, mode) = preceded(
+        tag("PROTOCOL "),
+        alt((
+            map(tag("ASCII"), |_| ProtocolMode::Ascii),
+            map(tag("BINARY"), |_| ProtocolMode::Binary),
+        )),
+    )
+    .parse(i)?;
+
+    Ok((i, Request::Protocol { mode }))
+}
+
+fn parse_get_or_set_pixel(i: &str) -> IResult<&str, Request<'_>> {
     let (i, (x, y)) = preceded(
         tag("PX "),
         separated_pair(
@@ -116,9 +375,79 @@ fn parse_get_or_set_pixel(i: &str) -> IResult<&str, Request> {
     }
 
     // As there are bytes left, this needs to be a SetPixel request
-    let (i, rgba) = preceded(char(' '), ascii_hex_u32).parse(i)?;
+    let (i, (rgba, alpha)) = preceded(char(' '), ascii_hex_color).parse(i)?;
+
+    Ok((i, Request::SetPixel { x, y, rgba, alpha }))
+}
+
+fn parse_rect(i: &str) -> IResult<&str, Request<'_>> {
+    let (i, (x, y, width, height, (rgba, alpha))) = preceded(
+        tag("RECT"),
+        (
+            preceded(char(' '), nom::character::complete::u16),
+            preceded(char(' '), nom::character::complete::u16),
+            preceded(char(' '), non_zero_u16),
+            preceded(char(' '), non_zero_u16),
+            preceded(char(' '), ascii_hex_color),
+        ),
+    )
+    .parse(i)?;
+
+    let shape = Shape::Rect {
+        x,
+        y,
+        width,
+        height,
+    };
+    Ok((i, Request::SetShape { shape, rgba, alpha }))
+}
+
+fn parse_line(i: &str) -> IResult<&str, Request<'_>> {
+    let (i, (x1, y1, x2, y2, (rgba, alpha))) = preceded(
+        tag("LINE"),
+        (
+            preceded(char(' '), nom::character::complete::u16),
+            preceded(char(' '), nom::character::complete::u16),
+            preceded(char(' '), nom::character::complete::u16),
+            preceded(char(' '), nom::character::complete::u16),
+            preceded(char(' '), ascii_hex_color),
+        ),
+    )
+    .parse(i)?;
+
+    let shape = Shape::Line { x1, y1, x2, y2 };
+    Ok((i, Request::SetShape { shape, rgba, alpha }))
+}
+
+fn parse_span(i: &str) -> IResult<&str, Request<'_>> {
+    let (i, (x, y, length)) = preceded(
+        tag("SPAN"),
+        (
+            preceded(char(' '), nom::character::complete::u16),
+            preceded(char(' '), nom::character::complete::u16),
+            preceded(char(' '), non_zero_u16),
+        ),
+    )
+    .parse(i)?;
+    let (i, colors) = count(preceded(char(' '), ascii_hex_color), length as usize).parse(i)?;
+
+    Ok((i, Request::SetSpan { x, y, colors }))
+}
 
-    Ok((i, Request::SetPixel { x, y, rgba }))
+fn non_zero_u16(i: &str) -> IResult<&str, u16> {
+    verify(nom::character::complete::u16, |n| *n > 0).parse(i)
+}
+
+/// Parses either `rrggbb` (fully opaque) or `rrggbbaa`
+fn ascii_hex_color(i: &str) -> IResult<&str, (u32, u8)> {
+    alt((
+        map_res(
+            take_while_m_n(8, 8, |c: char| c.is_ascii_hexdigit()),
+            |hex: &str| u32::from_str_radix(hex, 16).map(|rgba| (rgba >> 8, rgba as u8)),
+        ),
+        map(ascii_hex_u32, |rgba| (rgba, u8::MAX)),
+    ))
+    .parse(i)
 }
 
 fn ascii_hex_u32(i: &str) -> IResult<&str, u32> {
@@ -128,3 +457,180 @@ fn ascii_hex_u32(i: &str) -> IResult<&str, u32> {
     )
     .parse(i)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_hex_color_with_alpha() {
+        assert_eq!(ascii_hex_color("11223380"), Ok(("", (0x112233, 0x80))));
+        assert_eq!(ascii_hex_color("aAbBcCff"), Ok(("", (0xaabbcc, 0xff))));
+        assert_eq!(ascii_hex_color("11223300"), Ok(("", (0x112233, 0))));
+        // Only the first 8 digits are part of the color
+        assert_eq!(ascii_hex_color("112233445"), Ok(("5", (0x112233, 0x44))));
+    }
+
+    #[test]
+    fn ascii_hex_color_without_alpha() {
+        assert_eq!(ascii_hex_color("112233"), Ok(("", (0x112233, u8::MAX))));
+        // 7 digits are an opaque color followed by garbage, which makes the request invalid
+        assert_eq!(ascii_hex_color("1122334"), Ok(("4", (0x112233, u8::MAX))));
+        assert!(ascii_hex_color("11223").is_err());
+        assert!(ascii_hex_color("11223g").is_err());
+    }
+
+    #[test]
+    fn rect() {
+        let Ok((
+            "",
+            Request::SetShape {
+                shape:
+                    Shape::Rect {
+                        x,
+                        y,
+                        width,
+                        height,
+                    },
+                rgba,
+                alpha,
+            },
+        )) = parse_rect("RECT 1 2 3 4 11223380")
+        else {
+            panic!("RECT should be parsed");
+        };
+        assert_eq!(
+            (x, y, width, height, rgba, alpha),
+            (1, 2, 3, 4, 0x112233, 0x80)
+        );
+
+        assert!(parse_rect("RECT 1 2 0 4 112233").is_err());
+        assert!(parse_rect("RECT 1 2 3 0 112233").is_err());
+        assert!(parse_rect("RECT 1 2 3 4").is_err());
+        assert!(parse_rect("RECT 1 2 65536 4 112233").is_err());
+    }
+
+    #[test]
+    fn line() {
+        let Ok((
+            "",
+            Request::SetShape {
+                shape: Shape::Line { x1, y1, x2, y2 },
+                rgba,
+                alpha,
+            },
+        )) = parse_line("LINE 5 6 0 0 112233")
+        else {
+            panic!("LINE should be parsed");
+        };
+        assert_eq!(
+            (x1, y1, x2, y2, rgba, alpha),
+            (5, 6, 0, 0, 0x112233, u8::MAX)
+        );
+
+        // A line from a pixel to itself is a single pixel
+        assert!(parse_line("LINE 5 6 5 6 112233").is_ok());
+        assert!(parse_line("LINE 5 6 0 112233").is_err());
+    }
+
+    #[test]
+    fn span() {
+        let Ok(("", Request::SetSpan { x, y, colors })) =
+            parse_span("SPAN 1 2 3 112233 44556680 778899")
+        else {
+            panic!("SPAN should be parsed");
+        };
+        assert_eq!((x, y), (1, 2));
+        assert_eq!(
+            colors,
+            [(0x112233, u8::MAX), (0x445566, 0x80), (0x778899, u8::MAX)]
+        );
+
+        assert!(parse_span("SPAN 1 2 0").is_err());
+        assert!(parse_span("SPAN 1 2 3 112233 445566").is_err());
+        // Colors beyond the given length are left over, which makes the request invalid
+        assert_eq!(
+            parse_span("SPAN 1 2 1 112233 445566").map(|(rest, _)| rest),
+            Ok(" 445566")
+        );
+    }
+
+    #[test]
+    fn set_pixel_with_alpha() {
+        let Ok(("", Request::SetPixel { x, y, rgba, alpha })) =
+            parse_request("PX 65535 65535 ffeedd80")
+        else {
+            panic!("PX with alpha should be parsed");
+        };
+        assert_eq!((x, y, rgba, alpha), (65535, 65535, 0xffeedd, 0x80));
+    }
+
+    #[test]
+    fn explain_unknown_commands() {
+        for (line, name) in [
+            ("FOO 1 2", "FOO"),
+            ("px 1 2 ff0000", "px"),
+            ("PXX 1 2 ff0000", "PXX"),
+            ("", ""),
+            (" PX 1 2 ff0000", ""),
+        ] {
+            assert!(parse_request(line).is_err(), "{line:?}");
+            let explanation = explain_invalid_request(line);
+            assert!(
+                matches!(explanation, InvalidRequest::UnknownCommand { command } if command == name),
+                "{line:?}: {explanation:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn explain_invalid_arguments() {
+        for (line, name) in [
+            ("PX 1", "PX"),
+            ("PX 1 2 red", "PX"),
+            ("PX 1 2 ff0000 trailing", "PX"),
+            ("LOGIN alice", "LOGIN"),
+            ("HELLO x", "HELLO"),
+            ("RECT 1 2 3", "RECT"),
+        ] {
+            let explanation = explain_invalid_request(line);
+            let InvalidRequest::InvalidArguments { command } = explanation else {
+                panic!("{line:?}: {explanation:?}");
+            };
+            assert_eq!(command.name, name);
+            assert!(!command.usages.is_empty());
+        }
+    }
+
+    #[test]
+    fn hello() {
+        assert!(matches!(
+            parse_request("HELLO"),
+            Ok(("", Request::Hello { version: None }))
+        ));
+        assert!(matches!(
+            parse_request("HELLO 2"),
+            Ok(("", Request::Hello { version: Some(2) }))
+        ));
+        // Versions that don't fit into 32 bits are invalid
+        assert!(!matches!(parse_request("HELLO 4294967296"), Ok(("", _))));
+    }
+
+    #[test]
+    fn capabilities_are_documented() {
+        let protocol = include_str!("../../PROTOCOL.md");
+        for (index, capability) in CAPABILITIES.iter().enumerate() {
+            assert!(
+                capability
+                    .bytes()
+                    .all(|b| b.is_ascii_uppercase() || b == b'_'),
+                "{capability}"
+            );
+            assert!(!CAPABILITIES[..index].contains(capability), "{capability}");
+            assert!(
+                protocol.contains(&format!("| `{capability}` |")),
+                "{capability} is not documented in PROTOCOL.md"
+            );
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use crate::config::{SchedulerConfig, SchedulingPolicyKind};
+
+/// Number of "virtual time" units a user with weight 1 advances per slot in the [`WeightedFairShare`] policy.
+/// It's divided by the weight of the user, so it should be divisible by many numbers.
+const STRIDE: u64 = 720_720;
+
+/// A user waiting for its next slot, as seen by a [`SchedulingPolicy`]
+pub struct WaitingUser<'a> {
+    pub username: &'a str,
+
+    /// Number of consecutive slots the user did not paint any pixels in
+    pub idle_slots: usize,
+}
+
+/// Decides which user gets the next slot.
+///
+/// The [`super::user_scheduler::UserScheduler`] keeps the waiting users ordered by how long ago they had their last
+/// slot (longest first) and asks the policy for the order in which they should get their next slots.
+pub trait SchedulingPolicy: Send {
+    fn kind(&self) -> SchedulingPolicyKind;
+
+    /// Returns the indices of the given users in the order they should get their slots, the first user gets the next
+    /// slot.
+    fn order(&self, users: &[WaitingUser<'_>]) -> Vec<usize>;
+
+    /// Called once the user at `index` of `users` got its slot, so that the policy can update its bookkeeping.
+    fn slot_started(&mut self, users: &[WaitingUser<'_>], index: usize);
+
+    /// Called once the user logged out, so that the policy can forget about it
+    fn user_left(&mut self, username: &str);
+}
+
+pub fn new_policy(
+    kind: SchedulingPolicyKind,
+    config: &SchedulerConfig,
+) -> Box<dyn SchedulingPolicy> {
+    match kind {
+        SchedulingPolicyKind::RoundRobin => Box::new(RoundRobin),
+        SchedulingPolicyKind::WeightedFairShare => Box::new(WeightedFairShare {
+            weights: config.weights.clone(),
+            passes: Default::default(),
+        }),
+        SchedulingPolicyKind::SkipIdle => Box::new(SkipIdle {
+            idle_slots: config.idle_slots,
+            skipped: Default::default(),
+        }),
+    }
+}
+
+/// Every user gets a slot in turn
+pub struct RoundRobin;
+
+impl SchedulingPolicy for RoundRobin {
+    fn kind(&self) -> SchedulingPolicyKind {
+        SchedulingPolicyKind::RoundRobin
+    }
+
+    fn order(&self, users: &[WaitingUser<'_>]) -> Vec<usize> {
+        (0..users.len()).collect()
+    }
+
+    fn slot_started(&mut self, _users: &[WaitingUser<'_>], _index: usize) {}
+
+    fn user_left(&mut self, _username: &str) {}
+}
+
+/// Users get slots proportional to their configured weight (stride scheduling).
+///
+/// Every user has a "pass", which advances by [`STRIDE`] divided by its weight for every slot it gets. The user with
+/// the lowest pass gets the next slot.
+pub struct WeightedFairShare {
+    weights: HashMap<String, u32>,
+    passes: HashMap<String, u64>,
+}
+
+impl WeightedFairShare {
+    fn pass(&self, users: &[WaitingUser<'_>], username: &str) -> u64 {
+        self.passes.get(username).copied().unwrap_or_else(|| {
+            // New users start at the lowest pass, so that they can't monopolize the slots to catch up
+            users
+                .iter()
+                .filter_map(|user| self.passes.get(user.username))
+                .min()
+                .copied()
+                .unwrap_or_default()
+        })
+    }
+}
+
+impl SchedulingPolicy for WeightedFairShare {
+    fn kind(&self) -> SchedulingPolicyKind {
+        SchedulingPolicyKind::WeightedFairShare
+    }
+
+    fn order(&self, users: &[WaitingUser<'_>]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..users.len()).collect();
+        // Stable sort, so that users with the same pass keep the round-robin order
+        order.sort_by_key(|&index| self.pass(users, users[index].username));
+        order
+    }
+
+    fn slot_started(&mut self, users: &[WaitingUser<'_>], index: usize) {
+        let username = users[index].username;
+        let weight = self.weights.get(username).copied().unwrap_or(1);
+        let pass = self.pass(users, username) + STRIDE / weight as u64;
+        self.passes.insert(username.to_owned(), pass);
+    }
+
+    fn user_left(&mut self, username: &str) {
+        self.passes.remove(username);
+    }
+}
+
+/// Users that did not paint anything in their last `idle_slots` slots are skipped in favor of active users.
+///
+/// An idle user still gets a slot after it was skipped `idle_slots` times, so that it has a chance to become active
+/// again.
+pub struct SkipIdle {
+    idle_slots: usize,
+    skipped: HashMap<String, usize>,
+}
+
+impl SkipIdle {
+    fn is_skipped(&self, user: &WaitingUser<'_>) -> bool {
+        user.idle_slots >= self.idle_slots
+            && self.skipped.get(user.username).copied().unwrap_or_default() < self.idle_slots
+    }
+}
+
+impl SchedulingPolicy for SkipIdle {
+    fn kind(&self) -> SchedulingPolicyKind {
+        SchedulingPolicyKind::SkipIdle
+    }
+
+    fn order(&self, users: &[WaitingUser<'_>]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..users.len()).collect();
+        order.sort_by_key(|&index| self.is_skipped(&users[index]));
+        order
+    }
+
+    fn slot_started(&mut self, users: &[WaitingUser<'_>], index: usize) {
+        // All idle users that waited longer than the chosen user were skipped
+        for user in &users[..index] {
+            if self.is_skipped(user) {
+                *self.skipped.entry(user.username.to_owned()).or_default() += 1;
+            }
+        }
+        self.skipped.remove(users[index].username);
+    }
+
+    fn user_left(&mut self, username: &str) {
+        self.skipped.remove(username);
+    }
+}
@@ -0,0 +1,296 @@
+/// Pixels set by a single request, which are accepted or rejected as a whole
+#[derive(Clone, Copy, Debug)]
+pub enum Shape {
+    Rect {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    },
+    Line {
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+    },
+}
+
+impl Shape {
+    pub fn pixel(x: u16, y: u16) -> Self {
+        Shape::Rect {
+            x,
+            y,
+            width: 1,
+            height: 1,
+        }
+    }
+
+    /// Number of pixels of the shape
+    pub fn num_pixels(&self) -> usize {
+        match *self {
+            Shape::Rect { width, height, .. } => width as usize * height as usize,
+            Shape::Line { x1, y1, x2, y2 } => x1.abs_diff(x2).max(y1.abs_diff(y2)) as usize + 1,
+        }
+    }
+
+    /// Returns the corner of the bounding box that is the farthest away from the origin, so the shape is within the
+    /// canvas in case this corner is. This can exceed the range of `u16` for big rectangles.
+    pub fn max_corner(&self) -> (u32, u32) {
+        match *self {
+            Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => (
+                x as u32 + (width as u32).saturating_sub(1),
+                y as u32 + (height as u32).saturating_sub(1),
+            ),
+            Shape::Line { x1, y1, x2, y2 } => (x1.max(x2) as u32, y1.max(y2) as u32),
+        }
+    }
+
+    /// Returns all pixels of the shape, only call this once the shape is known to be within the canvas
+    pub fn pixels(&self) -> ShapePixels {
+        match *self {
+            Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => ShapePixels::Rect {
+                x_start: x,
+                x_end: x + width,
+                y_end: y + height,
+                x,
+                y,
+            },
+            Shape::Line { x1, y1, x2, y2 } => {
+                let dx = (x2 as i32 - x1 as i32).abs();
+                let dy = -(y2 as i32 - y1 as i32).abs();
+                ShapePixels::Line {
+                    x: x1 as i32,
+                    y: y1 as i32,
+                    x2: x2 as i32,
+                    y2: y2 as i32,
+                    dx,
+                    dy,
+                    step_x: if x1 < x2 { 1 } else { -1 },
+                    step_y: if y1 < y2 { 1 } else { -1 },
+                    error: dx + dy,
+                    done: false,
+                }
+            }
+        }
+    }
+}
+
+/// Iterator over the pixels of a [`Shape`], rectangles row by row and lines from start to end
+pub enum ShapePixels {
+    Rect {
+        x_start: u16,
+        x_end: u16,
+        y_end: u16,
+        x: u16,
+        y: u16,
+    },
+    /// Bresenham's line algorithm
+    Line {
+        x: i32,
+        y: i32,
+        x2: i32,
+        y2: i32,
+        dx: i32,
+        dy: i32,
+        step_x: i32,
+        step_y: i32,
+        error: i32,
+        done: bool,
+    },
+}
+
+impl Iterator for ShapePixels {
+    type Item = (u16, u16);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            ShapePixels::Rect {
+                x_start,
+                x_end,
+                y_end,
+                x,
+                y,
+            } => {
+                if *x == *x_end {
+                    *x = *x_start;
+                    *y += 1;
+                }
+                if *y >= *y_end || *x_start == *x_end {
+                    return None;
+                }
+                let pixel = (*x, *y);
+                *x += 1;
+                Some(pixel)
+            }
+            ShapePixels::Line {
+                x,
+                y,
+                x2,
+                y2,
+                dx,
+                dy,
+                step_x,
+                step_y,
+                error,
+                done,
+            } => {
+                if *done {
+                    return None;
+                }
+                let pixel = (*x as u16, *y as u16);
+                if *x == *x2 && *y == *y2 {
+                    *done = true;
+                } else {
+                    let doubled_error = 2 * *error;
+                    if doubled_error >= *dy {
+                        *error += *dy;
+                        *x += *step_x;
+                    }
+                    if doubled_error <= *dx {
+                        *error += *dx;
+                        *y += *step_y;
+                    }
+                }
+                Some(pixel)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> Shape {
+        Shape::Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Checks that the line is connected, goes from start to end and matches [`Shape::num_pixels`]
+    fn check_line(x1: u16, y1: u16, x2: u16, y2: u16) {
+        let shape = Shape::Line { x1, y1, x2, y2 };
+        let pixels: Vec<_> = shape.pixels().collect();
+
+        assert_eq!(pixels.len(), shape.num_pixels(), "{shape:?}");
+        assert_eq!(pixels.first(), Some(&(x1, y1)), "{shape:?}");
+        assert_eq!(pixels.last(), Some(&(x2, y2)), "{shape:?}");
+        assert_eq!(
+            pixels.iter().collect::<HashSet<_>>().len(),
+            pixels.len(),
+            "{shape:?} contains a pixel twice"
+        );
+        for pair in pixels.windows(2) {
+            let [(ax, ay), (bx, by)] = [pair[0], pair[1]];
+            assert!(
+                ax.abs_diff(bx) <= 1 && ay.abs_diff(by) <= 1,
+                "{shape:?} has a gap between {:?} and {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+        let (max_x, max_y) = shape.max_corner();
+        assert!(pixels
+            .iter()
+            .all(|&(x, y)| x as u32 <= max_x && y as u32 <= max_y));
+    }
+
+    #[test]
+    fn lines_in_all_octants() {
+        let (cx, cy) = (10, 10);
+        for (dx, dy) in [
+            // Flat and steep lines in all octants
+            (7, 3),
+            (3, 7),
+            (-3, 7),
+            (-7, 3),
+            (-7, -3),
+            (-3, -7),
+            (3, -7),
+            (7, -3),
+            // Axis-aligned and diagonal lines
+            (5, 0),
+            (-5, 0),
+            (0, 5),
+            (0, -5),
+            (4, 4),
+            (-4, 4),
+            (-4, -4),
+            (4, -4),
+        ] {
+            check_line(cx, cy, (cx as i32 + dx) as u16, (cy as i32 + dy) as u16);
+        }
+    }
+
+    #[test]
+    fn line_pixels() {
+        let shape = Shape::Line {
+            x1: 0,
+            y1: 0,
+            x2: 5,
+            y2: 2,
+        };
+        assert_eq!(shape.num_pixels(), 6);
+        // The pixels closest to the ideal line y = 0.4 * x
+        assert_eq!(
+            shape.pixels().collect::<Vec<_>>(),
+            [(0, 0), (1, 0), (2, 1), (3, 1), (4, 2), (5, 2)]
+        );
+    }
+
+    #[test]
+    fn single_pixel_line() {
+        check_line(3, 4, 3, 4);
+        assert_eq!(
+            Shape::Line {
+                x1: 3,
+                y1: 4,
+                x2: 3,
+                y2: 4
+            }
+            .pixels()
+            .collect::<Vec<_>>(),
+            [(3, 4)]
+        );
+    }
+
+    #[test]
+    fn rect_pixels() {
+        let shape = rect(1, 2, 3, 2);
+        assert_eq!(shape.num_pixels(), 6);
+        assert_eq!(
+            shape.pixels().collect::<Vec<_>>(),
+            [(1, 2), (2, 2), (3, 2), (1, 3), (2, 3), (3, 3)]
+        );
+        assert_eq!(shape.max_corner(), (3, 3));
+        assert_eq!(Shape::pixel(5, 6).pixels().collect::<Vec<_>>(), [(5, 6)]);
+    }
+
+    #[test]
+    fn zero_size_rects() {
+        for shape in [rect(1, 2, 0, 3), rect(1, 2, 3, 0), rect(1, 2, 0, 0)] {
+            assert_eq!(shape.num_pixels(), 0, "{shape:?}");
+            assert_eq!(shape.pixels().count(), 0, "{shape:?}");
+        }
+    }
+
+    #[test]
+    fn max_corner_exceeds_u16() {
+        assert_eq!(rect(65535, 65535, 65535, 2).max_corner(), (131069, 65536));
+    }
+}
@@ -1,4 +1,7 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Context;
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
@@ -10,20 +13,21 @@ use tokio::{
 };
 use tracing::{debug, trace};
 
-const USERS_SAVE_FILE: &str = "./users.json";
-
 pub struct UserManager {
     /// Key: Username
     /// Value: Hash
     users: RwLock<HashMap<String, String>>,
+
+    users_save_file: PathBuf,
 }
 
 impl UserManager {
-    pub async fn new_from_save_file() -> anyhow::Result<Self> {
-        let users = if Path::new(USERS_SAVE_FILE).exists() {
-            let mut file = File::open(USERS_SAVE_FILE)
+    pub async fn new_from_save_file(users_save_file: &Path) -> anyhow::Result<Self> {
+        let display_file = users_save_file.display();
+        let users = if users_save_file.exists() {
+            let mut file = File::open(users_save_file)
                 .await
-                .context(format!("Failed to read users save file {USERS_SAVE_FILE}"))?;
+                .context(format!("Failed to read users save file {display_file}"))?;
 
             // As the file is not so big, I'm fine with reading it into a buffer.
             // I want to avoid taking a dependency on e.g. https://github.com/carllerche/tokio-serde
@@ -31,12 +35,12 @@ impl UserManager {
                 file.metadata()
                     .await
                     .context(format!(
-                        "Failed to read metadata of users save file {USERS_SAVE_FILE}"
+                        "Failed to read metadata of users save file {display_file}"
                     ))?
                     .len() as usize,
             );
             file.read_to_end(&mut contents).await.context(format!(
-                "Failed to read from users save file {USERS_SAVE_FILE}"
+                "Failed to read from users save file {display_file}"
             ))?;
 
             serde_json::from_slice(&contents).context("Failed to deserialize users save file")?
@@ -46,6 +50,7 @@ impl UserManager {
 
         Ok(Self {
             users: RwLock::new(users),
+            users_save_file: users_save_file.to_owned(),
         })
     }
 
@@ -54,12 +59,13 @@ impl UserManager {
             .context("Failed to serialize users save file")?;
         let num_bytes = content.len();
 
-        let mut file = File::create(USERS_SAVE_FILE)
+        let display_file = self.users_save_file.display();
+        let mut file = File::create(&self.users_save_file)
+            .await
+            .context(format!("Failed to open users save file {display_file}"))?;
+        file.write_all(&content)
             .await
-            .context(format!("Failed to open users save file {USERS_SAVE_FILE}"))?;
-        file.write_all(&content).await.context(format!(
-            "Failed to write to users save file {USERS_SAVE_FILE}"
-        ))?;
+            .context(format!("Failed to write to users save file {display_file}"))?;
 
         trace!(num_bytes, "Written users save file");
 
@@ -1,105 +1,849 @@
-use std::{collections::VecDeque, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    mem,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::Context;
+use serde::Serialize;
 use tokio::{
-    sync::{mpsc, RwLock},
-    time::interval,
+    sync::{oneshot, watch, Mutex, RwLock},
+    time::{sleep_until, Instant},
 };
-use tracing::trace;
+use tracing::{debug, info, trace};
 
-use super::client_connection::SlotEvent;
+use super::{
+    client_connection::{CloseReason, Slot, SlotLimits, SlotStatus},
+    scheduling_policy::{new_policy, SchedulingPolicy, WaitingUser},
+};
 use crate::{
     app_state::AppState,
+    config::{DuplicateLoginPolicy, SchedulerConfig, SchedulingPolicyKind},
+    journal::unix_millis_now,
+    metrics,
     proto::{web_socket_message::Payload, CurrentlyPaintingClient, WebSocketMessage},
 };
 
 pub struct UserScheduler {
     shared_state: Arc<AppState>,
+
+    /// The users currently painting are at the front, all other users are ordered by how long ago they had their last
+    /// slot (longest first)
     users_queue: RwLock<VecDeque<ActiveUser>>,
+    policy: Mutex<Box<dyn SchedulingPolicy>>,
 
-    slot_duration: Duration,
+    /// No slots are started while paused
+    paused: AtomicBool,
+
+    /// Whether the viewers were told about the pause, so that they are only told when it changes
+    pause_announced: AtomicBool,
+
+    config: SchedulerConfig,
 }
 
 struct ActiveUser {
     username: String,
-    slot_tx: mpsc::Sender<SlotEvent>,
+    peer_ip: IpAddr,
+    slot_status_tx: watch::Sender<SlotStatus>,
+
+    /// Tells the connection to close, [`None`] once it was told so
+    close_tx: Option<oneshot::Sender<CloseReason>>,
+    painting: bool,
+
+    /// Number of pixels painted in the current slot, counted up by the client connection
+    painted_in_slot: Arc<AtomicUsize>,
+
+    /// Number of consecutive slots the user did not paint any pixels in
+    idle_slots: usize,
+
+    /// Number of upcoming slots the user has to skip as a penalty
+    skip_slots: usize,
+}
+
+impl ActiveUser {
+    fn waiting(&self) -> WaitingUser<'_> {
+        WaitingUser {
+            username: &self.username,
+            idle_slots: self.idle_slots,
+        }
+    }
+}
+
+/// A logged in user, as shown in the admin API
+#[derive(Serialize)]
+pub struct ConnectedUser {
+    pub username: String,
+    pub ip: IpAddr,
+    pub painting: bool,
+    pub idle_slots: usize,
+    pub skip_slots: usize,
+}
+
+/// Outcome of [`UserScheduler::register_user`]
+pub enum Registration {
+    Registered,
+
+    /// The user is already logged in on another connection and the login was rejected
+    AlreadyConnected,
 }
 
 impl UserScheduler {
-    pub fn new(shared_state: Arc<AppState>, slot_duration: Duration) -> Self {
+    pub fn new(shared_state: Arc<AppState>, config: &SchedulerConfig) -> Self {
         Self {
             shared_state,
             users_queue: Default::default(),
-            slot_duration,
+            policy: Mutex::new(new_policy(config.policy, config)),
+            paused: AtomicBool::new(false),
+            pause_announced: AtomicBool::new(false),
+            config: config.clone(),
         }
     }
 
-    /// Registers the given user.
+    pub async fn policy(&self) -> SchedulingPolicyKind {
+        self.policy.lock().await.kind()
+    }
+
+    /// Switches to the given scheduling policy, which decides about all following slots
+    pub async fn set_policy(&self, kind: SchedulingPolicyKind) {
+        *self.policy.lock().await = new_policy(kind, &self.config);
+        info!(policy = ?kind, "Switched scheduling policy");
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Pauses or resumes the scheduler. While paused, the running slots end as usual, but no new slots are started.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        info!(paused, "Changed pause state of the scheduler");
+    }
+
+    /// Returns all logged in users in the order of the queue, the users currently painting come first
+    pub async fn connected_users(&self) -> Vec<ConnectedUser> {
+        self.users_queue
+            .read()
+            .await
+            .iter()
+            .map(|user| ConnectedUser {
+                username: user.username.clone(),
+                ip: user.peer_ip,
+                painting: user.painting,
+                idle_slots: user.idle_slots,
+                skip_slots: user.skip_slots,
+            })
+            .collect()
+    }
+
+    /// Closes the connections of all users matching the given filter
+    pub async fn kick(&self, filter: impl Fn(&str, IpAddr) -> bool) -> KickOutcome {
+        let mut users_queue = self.users_queue.write().await;
+        let mut outcome = KickOutcome::default();
+        for user in users_queue
+            .iter_mut()
+            .filter(|user| filter(&user.username, user.peer_ip))
+        {
+            if close_connection(user, CloseReason::Kicked) {
+                outcome.kicked.push(user.username.clone());
+            } else {
+                outcome.not_delivered.push(user.username.clone());
+            }
+        }
+
+        outcome
+    }
+
+    /// Registers the given user. Its slots are published to `slot_status_tx`, `close_tx` tells the connection to close
+    /// (e.g. in case it was kicked).
     ///
-    /// Returns
-    /// 1. A receiver when the slot for the given user *starts*
-    /// 2. A receiver when the slot for the given user *ends*
-    pub async fn register_user(&self, username: &str, slot_tx: mpsc::Sender<SlotEvent>) {
-        let active_user = ActiveUser {
-            username: username.to_owned(),
-            slot_tx,
+    /// The connection counts up `painted_in_slot` for every pixel painted, users that did not paint anything in a slot
+    /// count as idle for the scheduling policy.
+    ///
+    /// Every user is only queued once. In case the user is already registered from another connection, the `policy`
+    /// decides whether the login is rejected or the other connection is replaced (keeping its place in the queue).
+    pub async fn register_user(
+        &self,
+        username: &str,
+        peer_ip: IpAddr,
+        slot_status_tx: watch::Sender<SlotStatus>,
+        close_tx: oneshot::Sender<CloseReason>,
+        painted_in_slot: Arc<AtomicUsize>,
+        policy: DuplicateLoginPolicy,
+    ) -> Registration {
+        let mut users_queue = self.users_queue.write().await;
+        let Some(existing) = users_queue.iter_mut().find(|u| u.username == username) else {
+            users_queue.push_back(ActiveUser {
+                username: username.to_owned(),
+                peer_ip,
+                slot_status_tx,
+                close_tx: Some(close_tx),
+                painting: false,
+                painted_in_slot,
+                idle_slots: 0,
+                skip_slots: 0,
+            });
+            metrics::LOGGED_IN_USERS.set(users_queue.len() as i64);
+            return Registration::Registered;
         };
-        self.users_queue.write().await.push_back(active_user);
+
+        match policy {
+            DuplicateLoginPolicy::Reject => Registration::AlreadyConnected,
+            DuplicateLoginPolicy::Replace => {
+                close_connection(existing, CloseReason::Replaced);
+                existing.slot_status_tx = slot_status_tx;
+                existing.close_tx = Some(close_tx);
+                existing.painted_in_slot = painted_in_slot;
+                existing.peer_ip = peer_ip;
+                Registration::Registered
+            }
+        }
     }
 
     /// Unregisters the given user.
-    pub async fn unregister_user(&self, username: &str) {
-        self.users_queue
+    ///
+    /// Only removes the user in case it is registered with the given `slot_status_tx`, so that closing a connection which was
+    /// replaced does not unregister the connection that replaced it.
+    pub async fn unregister_user(
+        &self,
+        username: &str,
+        slot_status_tx: &watch::Sender<SlotStatus>,
+    ) {
+        let mut users_queue = self.users_queue.write().await;
+        let num_users = users_queue.len();
+        users_queue
+            .retain(|u| u.username != username || !u.slot_status_tx.same_channel(slot_status_tx));
+        if users_queue.len() < num_users {
+            self.policy.lock().await.user_left(username);
+        }
+        metrics::LOGGED_IN_USERS.set(users_queue.len() as i64);
+    }
+
+    /// Lets the given user skip its next `slots` slots, e.g. as a penalty for a rule violation
+    pub async fn skip_slots(&self, username: &str, slots: usize) {
+        if let Some(user) = self
+            .users_queue
             .write()
             .await
-            .retain(|u| u.username != username);
+            .iter_mut()
+            .find(|u| u.username == username)
+        {
+            user.skip_slots = user.skip_slots.max(slots);
+        }
     }
 
+    /// Returns the limits of the next slot, which depend on the number of users in case adaptive slots are enabled
+    fn slot_limits(&self, num_users: usize) -> SlotLimits {
+        let adaptive = &self.config.adaptive;
+        if !adaptive.enabled || num_users == 0 {
+            return SlotLimits {
+                max_pixels: self.config.max_pixels_per_slot,
+                duration: self.config.slot_duration,
+            };
+        }
+
+        let num_users = u32::try_from(num_users).unwrap_or(u32::MAX);
+        let duration = (adaptive.round_duration / num_users)
+            .clamp(adaptive.min_slot_duration, adaptive.max_slot_duration);
+
+        // Longer slots allow for more pixels, so we scale the pixels linearly with the slot duration
+        let duration_range = adaptive.max_slot_duration - adaptive.min_slot_duration;
+        let progress = if duration_range.is_zero() {
+            1.0
+        } else {
+            (duration - adaptive.min_slot_duration).as_secs_f64() / duration_range.as_secs_f64()
+        };
+        let pixels_range = adaptive.max_pixels_per_slot - adaptive.min_pixels_per_slot;
+        let max_pixels =
+            adaptive.min_pixels_per_slot + (pixels_range as f64 * progress).round() as usize;
+
+        SlotLimits {
+            max_pixels,
+            duration,
+        }
+    }
+
+    /// Runs the slots. The slot boundaries follow a fixed schedule on the monotonic clock, so they don't drift.
     pub async fn run(&self) -> anyhow::Result<()> {
-        let mut interval = interval(self.slot_duration);
+        let mut slot_start = Instant::now();
 
         loop {
-            let mut users_queue = self.users_queue.write().await;
-
-            // Stop previous user
-            if let Some(prev) = users_queue.pop_front() {
-                trace!(username = prev.username, "Closing slot for");
+            let slot = self.next_slot().await;
+            let slot_end = slot_start + slot.limits.duration;
+            let deadline_unix_ms = unix_millis_now()
+                + slot_end
+                    .saturating_duration_since(Instant::now())
+                    .as_millis() as u64;
 
-                if prev.slot_tx.send(SlotEvent::SlotEnd).await.is_ok() {
-                    // Put user back in queue (all the way at the back)
-                    users_queue.push_back(prev);
-                }
+            // Publishing never waits for the connections, so a single slow client can not delay the slots of everyone
+            // else. The connections catch up with the latest status once they are ready.
+            for (_, slot_status_tx) in &slot.ended {
+                slot_status_tx.send_modify(|status| status.slot = None);
+            }
+            for (_, slot_status_tx) in &slot.started {
+                slot_status_tx.send_modify(|status| {
+                    status.epoch += 1;
+                    status.slot = Some(Slot {
+                        limits: slot.limits,
+                        deadline_unix_ms,
+                    });
+                });
             }
 
-            if let Some(next) = users_queue.front() {
-                trace!(username = next.username, "Next users turn");
-
-                if next.slot_tx.send(SlotEvent::SlotStart).await.is_err() {
-                    self.unregister_user(&next.username).await;
-                }
-
-                let upcoming_users = users_queue
-                    .iter()
-                    .skip(1)
-                    .take(10)
-                    .map(|user| user.username.clone())
-                    .collect();
-                let ws_message = WebSocketMessage {
-                    payload: Some(Payload::CurrentlyPaintingClient(CurrentlyPaintingClient {
-                        currently_painting: next.username.clone(),
-                        upcoming: upcoming_users,
-                    })),
-                };
+            if let Some(ws_message) = slot.ws_message {
                 self.shared_state
                     .ws_message_tx
                     .send(ws_message)
                     .await
                     .context("Failed to send update to websocket message channel")?;
+            }
+
+            sleep_until(slot_end).await;
+
+            // In case we fell behind more than a slot (e.g. because the runtime was busy), we don't try to catch up
+            // with a burst of tiny slots, but start over from now
+            let now = Instant::now();
+            slot_start = if now > slot_end + slot.limits.duration {
+                now
             } else {
-                trace!("No user playing, no one for the next slot");
+                slot_end
+            };
+        }
+    }
+
+    /// Ends the current slots and picks the users for the next slots.
+    ///
+    /// The users are not notified here, so that we never wait for a client while holding the queue lock.
+    async fn next_slot(&self) -> NextSlot {
+        let mut users_queue = self.users_queue.write().await;
+        let mut policy = self.policy.lock().await;
+
+        users_queue.retain(|user| {
+            let closed = user.slot_status_tx.is_closed();
+            if closed {
+                trace!(
+                    username = user.username,
+                    "Dropping user with closed connection"
+                );
+                policy.user_left(&user.username);
+            }
+            !closed
+        });
+        metrics::LOGGED_IN_USERS.set(users_queue.len() as i64);
+
+        // End the previous slots
+        let (painted, waiting): (VecDeque<_>, VecDeque<_>) = mem::take(&mut *users_queue)
+            .into_iter()
+            .partition(|u| u.painting);
+        *users_queue = waiting;
+        let mut ended = Vec::with_capacity(painted.len());
+        for mut prev in painted {
+            trace!(username = prev.username, "Closing slot for");
+
+            prev.painting = false;
+            if prev.painted_in_slot.swap(0, Ordering::Relaxed) == 0 {
+                prev.idle_slots += 1;
+            } else {
+                prev.idle_slots = 0;
+            }
+
+            ended.push((prev.username.clone(), prev.slot_status_tx.clone()));
+            // Put user back in queue (all the way at the back)
+            users_queue.push_back(prev);
+        }
+
+        // Pick the users for the next slots
+        let parallel_slots = self.config.parallel_slots;
+        let limits = self.slot_limits(users_queue.len().div_ceil(parallel_slots));
+        let mut started = Vec::with_capacity(parallel_slots);
+        // Users skipping this slot are set aside, so that they skip at most one slot per tick
+        let mut skipped = Vec::new();
+        let paused = self.paused();
+        while !paused && started.len() < parallel_slots {
+            let num_painting = started.len();
+            let waiting: Vec<_> = users_queue
+                .iter()
+                .skip(num_painting)
+                .map(ActiveUser::waiting)
+                .collect();
+            let Some(&index) = policy.order(&waiting).first() else {
+                break;
+            };
+            policy.slot_started(&waiting, index);
+
+            // The painting users are always at the front
+            let mut next = users_queue
+                .remove(num_painting + index)
+                .expect("index is within queue");
+            if next.skip_slots > 0 {
+                // The user misses its turn, which counts as if it had its slot
+                trace!(username = next.username, "Skipping slot of penalized user");
+                next.skip_slots -= 1;
+                skipped.push(next);
+                continue;
             }
+            next.painting = true;
+            metrics::SLOTS.inc();
+            started.push((next.username.clone(), next.slot_status_tx.clone()));
+            users_queue.insert(num_painting, next);
+        }
+        users_queue.extend(skipped);
+
+        let pause_changed = self.pause_announced.swap(paused, Ordering::Relaxed) != paused;
+        let ws_message = if paused {
+            trace!("Scheduler is paused, no one for the next slot");
+            pause_changed.then(|| WebSocketMessage {
+                payload: Some(Payload::CurrentlyPaintingClient(CurrentlyPaintingClient {
+                    currently_painting: String::new(),
+                    upcoming: Vec::new(),
+                    currently_painting_clients: Vec::new(),
+                    paused: true,
+                })),
+            })
+        } else if started.is_empty() && !pause_changed {
+            trace!("No user playing, no one for the next slot");
+            None
+        } else {
+            let currently_painting: Vec<_> = started
+                .iter()
+                .map(|(username, _)| username.clone())
+                .collect();
+            trace!(?currently_painting, ?limits, "Next users turn");
+
+            let waiting: Vec<_> = users_queue
+                .iter()
+                .skip(started.len())
+                .map(ActiveUser::waiting)
+                .collect();
+            let upcoming_users = policy
+                .order(&waiting)
+                .into_iter()
+                .take(10)
+                .map(|index| waiting[index].username.to_owned())
+                .collect();
+
+            Some(WebSocketMessage {
+                payload: Some(Payload::CurrentlyPaintingClient(CurrentlyPaintingClient {
+                    // Empty in case no one is playing after a pause
+                    currently_painting: currently_painting.first().cloned().unwrap_or_default(),
+                    upcoming: upcoming_users,
+                    currently_painting_clients: currently_painting,
+                    paused: false,
+                })),
+            })
+        };
 
-            interval.tick().await;
+        NextSlot {
+            limits,
+            ended,
+            started,
+            ws_message,
         }
     }
 }
+
+/// Outcome of [`UserScheduler::next_slot`]
+struct NextSlot {
+    limits: SlotLimits,
+
+    /// Users whose slot ended
+    ended: Vec<(String, watch::Sender<SlotStatus>)>,
+
+    /// Users whose slot starts
+    started: Vec<(String, watch::Sender<SlotStatus>)>,
+
+    ws_message: Option<WebSocketMessage>,
+}
+
+/// Outcome of [`UserScheduler::kick`]
+#[derive(Default)]
+pub struct KickOutcome {
+    /// Users whose connections were told to close
+    pub kicked: Vec<String>,
+
+    /// Users whose connections could not be told to close, as they were already closing (e.g. because they were kicked
+    /// before)
+    pub not_delivered: Vec<String>,
+}
+
+/// Tells the connection of the given user to close, returns whether the connection got the message
+fn close_connection(user: &mut ActiveUser, reason: CloseReason) -> bool {
+    let delivered = user
+        .close_tx
+        .take()
+        .is_some_and(|close_tx| close_tx.send(reason).is_ok());
+    if !delivered {
+        debug!(
+            username = user.username,
+            ?reason,
+            "Failed to tell connection to close, as it is already closing"
+        );
+    }
+
+    delivered
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::Ipv4Addr, time::Duration};
+
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::*;
+    use crate::config::{AdaptiveSlotConfig, Config};
+
+    fn scheduler(config: SchedulerConfig) -> UserScheduler {
+        let mut app_config = Config::default();
+        app_config.snapshot.enabled = false;
+        app_config.journal.enabled = false;
+        let (ws_message_tx, _) = mpsc::channel(1);
+        let (_, compressed_ws_message_rx) = broadcast::channel(1);
+        let shared_state = AppState::new(&app_config, ws_message_tx, compressed_ws_message_rx)
+            .expect("app state without snapshot and journal can be created");
+
+        UserScheduler::new(Arc::new(shared_state), &config)
+    }
+
+    /// Registers the given user, the returned receiver needs to be kept so that the user is not dropped as closed
+    async fn register(scheduler: &UserScheduler, username: &str) -> watch::Receiver<SlotStatus> {
+        let (slot_status_tx, slot_status_rx) = watch::channel(SlotStatus::default());
+        scheduler
+            .register_user(
+                username,
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                slot_status_tx,
+                oneshot::channel().0,
+                Default::default(),
+                DuplicateLoginPolicy::Reject,
+            )
+            .await;
+
+        slot_status_rx
+    }
+
+    async fn started(scheduler: &UserScheduler) -> Vec<String> {
+        scheduler
+            .next_slot()
+            .await
+            .started
+            .into_iter()
+            .map(|(username, _)| username)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn penalized_user_alone_skips_its_slots() {
+        let scheduler = scheduler(SchedulerConfig::default());
+        let _slot_rx = register(&scheduler, "alice").await;
+        scheduler.skip_slots("alice", 3).await;
+
+        for _ in 0..3 {
+            assert!(started(&scheduler).await.is_empty());
+        }
+        assert_eq!(started(&scheduler).await, ["alice"]);
+    }
+
+    #[tokio::test]
+    async fn penalized_user_skips_once_per_tick_with_parallel_slots() {
+        let scheduler = scheduler(SchedulerConfig {
+            parallel_slots: 2,
+            ..Default::default()
+        });
+        let _alice_rx = register(&scheduler, "alice").await;
+        let _bob_rx = register(&scheduler, "bob").await;
+        scheduler.skip_slots("alice", 2).await;
+
+        assert_eq!(started(&scheduler).await, ["bob"]);
+        assert_eq!(started(&scheduler).await, ["bob"]);
+        let mut both = started(&scheduler).await;
+        both.sort();
+        assert_eq!(both, ["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn weighted_fair_share_with_parallel_slots() {
+        let scheduler = scheduler(SchedulerConfig {
+            policy: SchedulingPolicyKind::WeightedFairShare,
+            parallel_slots: 2,
+            weights: HashMap::from([("alice".to_owned(), 3)]),
+            ..Default::default()
+        });
+        let _alice_rx = register(&scheduler, "alice").await;
+        let _bob_rx = register(&scheduler, "bob").await;
+        let _carol_rx = register(&scheduler, "carol").await;
+
+        let mut slots = HashMap::<String, usize>::new();
+        for _ in 0..100 {
+            for username in started(&scheduler).await {
+                *slots.entry(username).or_default() += 1;
+            }
+        }
+
+        // Alice would deserve 3/5 of the 200 slots, but can only have one slot at a time
+        assert_eq!(slots["alice"], 100);
+        assert_eq!(slots["bob"], 50);
+        assert_eq!(slots["carol"], 50);
+    }
+
+    #[tokio::test]
+    async fn kick_reports_whether_it_was_delivered() {
+        let scheduler = scheduler(SchedulerConfig::default());
+        let (slot_status_tx, _slot_status_rx) = watch::channel(SlotStatus::default());
+        let (close_tx, mut close_rx) = oneshot::channel();
+        scheduler
+            .register_user(
+                "alice",
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                slot_status_tx,
+                close_tx,
+                Default::default(),
+                DuplicateLoginPolicy::Reject,
+            )
+            .await;
+
+        let outcome = scheduler.kick(|username, _| username == "alice").await;
+        assert_eq!(outcome.kicked, ["alice"]);
+        assert!(outcome.not_delivered.is_empty());
+        assert!(matches!(close_rx.try_recv(), Ok(CloseReason::Kicked)));
+
+        // The connection is still closing, so kicking it again is not delivered
+        let outcome = scheduler.kick(|username, _| username == "alice").await;
+        assert!(outcome.kicked.is_empty());
+        assert_eq!(outcome.not_delivered, ["alice"]);
+    }
+
+    fn usernames(users: Vec<ConnectedUser>) -> Vec<String> {
+        users.into_iter().map(|user| user.username).collect()
+    }
+
+    #[tokio::test]
+    async fn users_are_unregistered_when_their_connection_closes() {
+        let scheduler = scheduler(SchedulerConfig::default());
+        let (alice_tx, _alice_rx) = watch::channel(SlotStatus::default());
+        scheduler
+            .register_user(
+                "alice",
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                alice_tx.clone(),
+                oneshot::channel().0,
+                Default::default(),
+                DuplicateLoginPolicy::Reject,
+            )
+            .await;
+        let bob_rx = register(&scheduler, "bob").await;
+        assert_eq!(
+            usernames(scheduler.connected_users().await),
+            ["alice", "bob"]
+        );
+
+        scheduler.unregister_user("alice", &alice_tx).await;
+        assert_eq!(usernames(scheduler.connected_users().await), ["bob"]);
+
+        // Connections that went away without unregistering are dropped with the next slot
+        drop(bob_rx);
+        assert!(started(&scheduler).await.is_empty());
+        assert!(scheduler.connected_users().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicate_login_is_rejected() {
+        let scheduler = scheduler(SchedulerConfig::default());
+        let (first_tx, _first_rx) = watch::channel(SlotStatus::default());
+        let (first_close_tx, mut first_close_rx) = oneshot::channel();
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        scheduler
+            .register_user(
+                "alice",
+                localhost,
+                first_tx.clone(),
+                first_close_tx,
+                Default::default(),
+                DuplicateLoginPolicy::Reject,
+            )
+            .await;
+
+        let (second_tx, _second_rx) = watch::channel(SlotStatus::default());
+        let registration = scheduler
+            .register_user(
+                "alice",
+                localhost,
+                second_tx.clone(),
+                oneshot::channel().0,
+                Default::default(),
+                DuplicateLoginPolicy::Reject,
+            )
+            .await;
+        assert!(matches!(registration, Registration::AlreadyConnected));
+        assert!(first_close_rx.try_recv().is_err());
+
+        // The rejected connection does not unregister the user when it closes
+        scheduler.unregister_user("alice", &second_tx).await;
+        assert_eq!(usernames(scheduler.connected_users().await), ["alice"]);
+        scheduler.unregister_user("alice", &first_tx).await;
+        assert!(scheduler.connected_users().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicate_login_replaces_the_other_connection() {
+        let scheduler = scheduler(SchedulerConfig::default());
+        let _bob_rx = register(&scheduler, "bob").await;
+        let (first_tx, _first_rx) = watch::channel(SlotStatus::default());
+        let (first_close_tx, mut first_close_rx) = oneshot::channel();
+        scheduler
+            .register_user(
+                "alice",
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                first_tx.clone(),
+                first_close_tx,
+                Default::default(),
+                DuplicateLoginPolicy::Replace,
+            )
+            .await;
+
+        let (second_tx, _second_rx) = watch::channel(SlotStatus::default());
+        let registration = scheduler
+            .register_user(
+                "alice",
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                second_tx.clone(),
+                oneshot::channel().0,
+                Default::default(),
+                DuplicateLoginPolicy::Replace,
+            )
+            .await;
+        assert!(matches!(registration, Registration::Registered));
+        assert!(matches!(
+            first_close_rx.try_recv(),
+            Ok(CloseReason::Replaced)
+        ));
+
+        // The user keeps its place in the queue
+        let users = scheduler.connected_users().await;
+        assert_eq!(users[1].ip, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(usernames(users), ["bob", "alice"]);
+
+        // Closing the replaced connection does not unregister the connection that replaced it
+        scheduler.unregister_user("alice", &first_tx).await;
+        assert_eq!(
+            usernames(scheduler.connected_users().await),
+            ["bob", "alice"]
+        );
+        scheduler.unregister_user("alice", &second_tx).await;
+        assert_eq!(usernames(scheduler.connected_users().await), ["bob"]);
+    }
+
+    fn adaptive_config(parallel_slots: usize) -> SchedulerConfig {
+        SchedulerConfig {
+            parallel_slots,
+            adaptive: AdaptiveSlotConfig {
+                enabled: true,
+                round_duration: Duration::from_secs(10),
+                min_slot_duration: Duration::from_secs(1),
+                max_slot_duration: Duration::from_secs(3),
+                min_pixels_per_slot: 1_000,
+                max_pixels_per_slot: 3_000,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn assert_limits(limits: SlotLimits, duration_ms: u64, max_pixels: usize) {
+        assert_eq!(limits.duration, Duration::from_millis(duration_ms));
+        assert_eq!(limits.max_pixels, max_pixels);
+    }
+
+    #[tokio::test]
+    async fn adaptive_slot_limits_scale_with_the_number_of_users() {
+        let scheduler = scheduler(adaptive_config(1));
+
+        // Few users get the longest slots, many users the shortest ones
+        assert_limits(scheduler.slot_limits(1), 3_000, 3_000);
+        assert_limits(scheduler.slot_limits(3), 3_000, 3_000);
+        assert_limits(scheduler.slot_limits(10), 1_000, 1_000);
+        assert_limits(scheduler.slot_limits(1_000), 1_000, 1_000);
+
+        // In between the pixels scale linearly with the duration
+        assert_limits(scheduler.slot_limits(5), 2_000, 2_000);
+        assert_limits(scheduler.slot_limits(4), 2_500, 2_500);
+
+        // Without users the fixed limits apply
+        let fixed = SchedulerConfig::default();
+        assert_limits(
+            scheduler.slot_limits(0),
+            fixed.slot_duration.as_millis() as u64,
+            fixed.max_pixels_per_slot,
+        );
+    }
+
+    #[tokio::test]
+    async fn adaptive_slot_limits_count_users_per_parallel_slot() {
+        let scheduler = scheduler(adaptive_config(2));
+        let mut _slot_rxs = Vec::new();
+        for username in [
+            "alice", "bob", "carol", "dave", "erin", "frank", "grace", "heidi",
+        ] {
+            _slot_rxs.push(register(&scheduler, username).await);
+        }
+
+        // 8 users in 2 parallel slots take as long as 4 users in a single slot
+        assert_limits(scheduler.next_slot().await.limits, 2_500, 2_500);
+    }
+
+    #[tokio::test]
+    async fn fixed_slot_limits_without_adaptive_slots() {
+        let config = SchedulerConfig {
+            adaptive: AdaptiveSlotConfig {
+                enabled: false,
+                ..adaptive_config(1).adaptive
+            },
+            ..Default::default()
+        };
+        let scheduler = scheduler(config.clone());
+
+        for num_users in [1, 5, 1_000] {
+            assert_limits(
+                scheduler.slot_limits(num_users),
+                config.slot_duration.as_millis() as u64,
+                config.max_pixels_per_slot,
+            );
+        }
+    }
+
+    fn paused_message(ws_message: Option<WebSocketMessage>) -> Option<bool> {
+        match ws_message?.payload? {
+            Payload::CurrentlyPaintingClient(client) => Some(client.paused),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn pause_is_only_announced_when_it_changes() {
+        let scheduler = scheduler(SchedulerConfig::default());
+
+        scheduler.set_paused(true);
+        assert_eq!(
+            paused_message(scheduler.next_slot().await.ws_message),
+            Some(true)
+        );
+        for _ in 0..3 {
+            assert_eq!(paused_message(scheduler.next_slot().await.ws_message), None);
+        }
+
+        // Resuming is announced even if no one is playing
+        scheduler.set_paused(false);
+        assert_eq!(
+            paused_message(scheduler.next_slot().await.ws_message),
+            Some(false)
+        );
+        assert_eq!(paused_message(scheduler.next_slot().await.ws_message), None);
+
+        let _alice_rx = register(&scheduler, "alice").await;
+        assert_eq!(
+            paused_message(scheduler.next_slot().await.ws_message),
+            Some(false)
+        );
+    }
+}
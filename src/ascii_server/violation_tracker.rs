@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    net::IpAddr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, time::Instant};
+use tracing::info;
+
+use crate::{
+    config::{Penalty, ViolationConfig},
+    journal::unix_millis_now,
+};
+
+/// Keeps track of the rule violations (e.g. exceeding the quota) per username and per IP address and decides about
+/// the penalties.
+///
+/// The penalties escalate with every offense as configured in [`ViolationConfig::penalties`]. Offenses are forgotten
+/// after [`ViolationConfig::forget_after`] without any new offense, bans are kept in memory only.
+pub struct ViolationTracker {
+    records: RwLock<HashMap<Offender, ViolationRecord>>,
+    config: ViolationConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Offender {
+    User(String),
+    Ip(IpAddr),
+}
+
+struct ViolationRecord {
+    offenses: usize,
+    last_offense: Instant,
+    ban: Option<Ban>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Ban {
+    Until(Instant),
+    Permanent,
+}
+
+/// The penalty for a single offense, as reported to the offender
+#[derive(Clone, Copy, Debug)]
+pub struct Sentence {
+    /// Number of recent offenses, including this one
+    pub offense: usize,
+    pub penalty: Penalty,
+}
+
+/// State of a single offender, as shown in the admin API
+#[derive(Serialize)]
+pub struct ViolationEntry {
+    pub offender: Offender,
+    pub offenses: usize,
+    pub ban: Option<BanEntry>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanEntry {
+    Until { unix_ms: u64 },
+    Permanent,
+}
+
+impl ViolationTracker {
+    pub fn new(config: &ViolationConfig) -> Self {
+        Self {
+            records: Default::default(),
+            config: config.clone(),
+        }
+    }
+
+    /// Records an offense of the given user connected from the given IP address and returns the penalty.
+    ///
+    /// The penalty escalates with the number of recent offenses of the user or the IP address, whichever is higher.
+    /// Bans apply to both of them.
+    pub async fn record_offense(&self, username: &str, ip: IpAddr) -> Sentence {
+        let now = Instant::now();
+        let mut records = self.records.write().await;
+
+        // Forget about old offenses, so that the map does not grow forever
+        records.retain(|_, record| !self.is_forgotten(record, now));
+
+        let mut offenders = vec![Offender::User(username.to_owned())];
+        if self.config.track_ips {
+            offenders.push(Offender::Ip(ip));
+        }
+
+        let mut offense = 0;
+        for offender in &offenders {
+            let record = records
+                .entry(offender.clone())
+                .or_insert_with(|| ViolationRecord {
+                    offenses: 0,
+                    last_offense: now,
+                    ban: None,
+                });
+            record.offenses += 1;
+            record.last_offense = now;
+            offense = offense.max(record.offenses);
+        }
+
+        let penalty = self.config.penalties[(offense - 1).min(self.config.penalties.len() - 1)];
+        let ban = match penalty {
+            Penalty::Disconnect | Penalty::SkipSlots(_) => None,
+            Penalty::Ban(duration) => Some(Ban::Until(now + duration)),
+            Penalty::PermanentBan => Some(Ban::Permanent),
+        };
+        if let Some(ban) = ban {
+            for offender in &offenders {
+                let record = records
+                    .get_mut(offender)
+                    .expect("record was inserted above");
+                record.ban = Some(match (record.ban, ban) {
+                    // Never shorten an existing ban
+                    (Some(Ban::Until(existing)), Ban::Until(new)) => Ban::Until(existing.max(new)),
+                    (Some(Ban::Permanent), _) | (_, Ban::Permanent) => Ban::Permanent,
+                    (None, ban) => ban,
+                });
+            }
+        }
+
+        info!(username, %ip, offense, ?penalty, "Penalizing rule violation");
+
+        Sentence { offense, penalty }
+    }
+
+    /// Bans the given offender for the given duration, or permanently in case no duration is given.
+    ///
+    /// In contrast to bans as a penalty, this replaces any existing ban.
+    pub async fn ban(&self, offender: Offender, duration: Option<Duration>) {
+        let now = Instant::now();
+        let ban = match duration {
+            Some(duration) => Ban::Until(now + duration),
+            None => Ban::Permanent,
+        };
+        info!(?offender, ?duration, "Banning");
+
+        self.records
+            .write()
+            .await
+            .entry(offender)
+            .or_insert_with(|| ViolationRecord {
+                offenses: 0,
+                last_offense: now,
+                ban: None,
+            })
+            .ban = Some(ban);
+    }
+
+    /// Lifts the ban of the given offender and forgets about its offenses. Returns whether the offender was known.
+    pub async fn unban(&self, offender: &Offender) -> bool {
+        info!(?offender, "Unbanning");
+        self.records.write().await.remove(offender).is_some()
+    }
+
+    /// Returns the ban of the given offender, in case it is currently banned
+    pub async fn active_ban(&self, offender: &Offender) -> Option<Ban> {
+        let now = Instant::now();
+        self.records
+            .read()
+            .await
+            .get(offender)
+            .and_then(|record| record.ban)
+            .filter(|ban| ban.is_active(now))
+    }
+
+    /// Returns all offenders with recent offenses or an active ban
+    pub async fn entries(&self) -> Vec<ViolationEntry> {
+        let now = Instant::now();
+        self.records
+            .read()
+            .await
+            .iter()
+            .filter(|(_, record)| !self.is_forgotten(record, now))
+            .map(|(offender, record)| ViolationEntry {
+                offender: offender.clone(),
+                offenses: record.offenses,
+                ban: record
+                    .ban
+                    .filter(|ban| ban.is_active(now))
+                    .map(|ban| match ban {
+                        Ban::Until(until) => BanEntry::Until {
+                            unix_ms: unix_millis_now() + (until - now).as_millis() as u64,
+                        },
+                        Ban::Permanent => BanEntry::Permanent,
+                    }),
+            })
+            .collect()
+    }
+
+    fn is_forgotten(&self, record: &ViolationRecord, now: Instant) -> bool {
+        now.duration_since(record.last_offense) > self.config.forget_after
+            && !record.ban.is_some_and(|ban| ban.is_active(now))
+    }
+}
+
+impl Ban {
+    fn is_active(&self, now: Instant) -> bool {
+        match self {
+            Ban::Until(until) => *until > now,
+            Ban::Permanent => true,
+        }
+    }
+}
+
+impl Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ban::Until(until) => {
+                // Sub-second precision is only noise for the client
+                let remaining = until.saturating_duration_since(Instant::now());
+                let remaining = Duration::from_secs(remaining.as_secs().max(1));
+                write!(f, "for another {}", humantime::format_duration(remaining))
+            }
+            Ban::Permanent => write!(f, "permanently"),
+        }
+    }
+}
+
+impl Display for Sentence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offense = self.offense;
+        match self.penalty {
+            Penalty::Disconnect => write!(f, "Offense #{offense}, closing the connection"),
+            Penalty::SkipSlots(slots) => write!(
+                f,
+                "Offense #{offense}, the pixels of this slot are discarded and you will skip your next {slots} slot(s). Pixels are ignored until your next START"
+            ),
+            Penalty::Ban(duration) => write!(
+                f,
+                "Offense #{offense}, you are banned for {}",
+                humantime::format_duration(duration)
+            ),
+            Penalty::PermanentBan => write!(f, "Offense #{offense}, you are banned permanently"),
+        }
+    }
+}
+
+impl Penalty {
+    /// Whether the connection of the offender is closed
+    pub fn closes_connection(&self) -> bool {
+        !matches!(self, Penalty::SkipSlots(_))
+    }
+}
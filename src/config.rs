@@ -0,0 +1,562 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use crate::timelapse::TimelapseArgs;
+
+/// The longest request with a fixed size we need to be able to receive, which is a `RECT` (or `LINE`) with alpha at the
+/// maximum coordinates. A smaller max input line length would make it impossible to send some valid requests.
+const MIN_INPUT_LINE_LENGTH: usize = "RECT 65535 65535 65535 65535 rrggbbaa".len();
+
+/// Command line arguments.
+///
+/// All arguments apart from `--config` override the corresponding setting from the config file. They can also be
+/// passed as environment variables.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// What to do, runs the server if no command is given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to the TOML config file. Settings missing from the file fall back to their defaults.
+    #[arg(short, long, env = "PIXELSTROM_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Width of the canvas in pixels
+    #[arg(long, env = "PIXELSTROM_WIDTH")]
+    pub width: Option<u16>,
+
+    /// Height of the canvas in pixels
+    #[arg(long, env = "PIXELSTROM_HEIGHT")]
+    pub height: Option<u16>,
+
+    /// Address the ASCII server listens on, e.g. `[::]:1234`
+    #[arg(long, env = "PIXELSTROM_ASCII_LISTENER_ADDRESS")]
+    pub ascii_listener_address: Option<SocketAddr>,
+
+    /// Address the HTTP server listens on, e.g. `[::]:3000`. Can be given multiple times to listen on multiple
+    /// addresses, e.g. `127.0.0.1:3000` and `[::1]:3000`.
+    #[arg(
+        long,
+        env = "PIXELSTROM_HTTP_LISTENER_ADDRESSES",
+        value_delimiter = ','
+    )]
+    pub http_listener_address: Vec<SocketAddr>,
+
+    /// Maximum number of open ASCII connections per IP address
+    #[arg(long, env = "PIXELSTROM_MAX_CONNECTIONS_PER_IP")]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Maximum number of characters a client can send before it needs to send a newline
+    #[arg(long, env = "PIXELSTROM_MAX_INPUT_LINE_LENGTH")]
+    pub max_input_line_length: Option<usize>,
+
+    /// File the registered users (and their password hashes) are stored in
+    #[arg(long, env = "PIXELSTROM_USERS_FILE")]
+    pub users_file: Option<PathBuf>,
+
+    /// Number of pixels a user is allowed to set during a single slot
+    #[arg(long, env = "PIXELSTROM_MAX_PIXELS_PER_SLOT")]
+    pub max_pixels_per_slot: Option<usize>,
+
+    /// Duration of a single slot, e.g. `500ms` or `1s`
+    #[arg(long, env = "PIXELSTROM_SLOT_DURATION", value_parser = humantime::parse_duration)]
+    pub slot_duration: Option<Duration>,
+
+    /// Policy deciding which user gets the next slot
+    #[arg(long, env = "PIXELSTROM_SCHEDULING_POLICY", value_enum)]
+    pub scheduling_policy: Option<SchedulingPolicyKind>,
+
+    /// Bearer token protecting the admin API. The admin API is disabled in case no token is configured.
+    #[arg(long, env = "PIXELSTROM_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the pixelstrom server
+    Serve,
+
+    /// Render a timelapse of the canvas from the paint journal
+    Timelapse(TimelapseArgs),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub canvas: CanvasConfig,
+    pub ascii_server: AsciiServerConfig,
+    pub http_server: HttpServerConfig,
+    pub scheduler: SchedulerConfig,
+    pub violations: ViolationConfig,
+    pub snapshot: SnapshotConfig,
+    pub journal: JournalConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CanvasConfig {
+    pub width: u16,
+    pub height: u16,
+
+    /// Parts of the canvas only some users are allowed to paint in
+    pub protected_regions: Vec<ProtectedRegion>,
+}
+
+/// Rectangle on the canvas in which only the allowed users can paint, e.g. for sponsor logos
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProtectedRegion {
+    /// Shown to users trying to paint in the region
+    pub name: String,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+
+    /// Users that are allowed to paint in the region, no one is allowed in case this is empty
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AsciiServerConfig {
+    pub listener_address: SocketAddr,
+    pub max_connections_per_ip: usize,
+    pub max_input_line_length: usize,
+    pub users_file: PathBuf,
+    pub duplicate_login_policy: DuplicateLoginPolicy,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpServerConfig {
+    pub listener_addresses: Vec<SocketAddr>,
+
+    /// [`None`] disables the admin API
+    pub admin_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    pub max_pixels_per_slot: usize,
+    #[serde(with = "humantime_serde")]
+    pub slot_duration: Duration,
+    pub policy: SchedulingPolicyKind,
+
+    /// Number of users painting at the same time
+    pub parallel_slots: usize,
+
+    /// How long after the end of a slot a late `DONE` is still accepted, can not be longer than the shortest slot
+    #[serde(with = "humantime_serde")]
+    pub grace_period: Duration,
+
+    /// Whether the pixels of a `DONE` received during the grace period are discarded instead of painted
+    pub discard_late_pixels: bool,
+
+    /// Weights of the users for the weighted fair share policy, users not listed have a weight of 1
+    pub weights: HashMap<String, u32>,
+
+    /// Number of consecutive slots without painting after which a user counts as idle for the skip idle policy
+    pub idle_slots: usize,
+
+    pub adaptive: AdaptiveSlotConfig,
+}
+
+/// Derives the slot duration and pixels per slot from the number of users instead of using fixed values
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptiveSlotConfig {
+    pub enabled: bool,
+
+    /// Every user should get a slot within this period, so the slot duration is this divided by the number of users
+    /// (multiplied by the number of parallel slots)
+    #[serde(with = "humantime_serde")]
+    pub round_duration: Duration,
+    #[serde(with = "humantime_serde")]
+    pub min_slot_duration: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_slot_duration: Duration,
+
+    /// Number of pixels allowed in the shortest slot, scaled linearly up to `max_pixels_per_slot` for the longest slot
+    pub min_pixels_per_slot: usize,
+    pub max_pixels_per_slot: usize,
+}
+
+/// Penalties for rule violations, such as exceeding the quota or painting outside of the own slot
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ViolationConfig {
+    /// The penalty for the n-th offense, the last one is repeated for all further offenses
+    pub penalties: Vec<Penalty>,
+
+    /// Offenses are forgotten after this long without a new offense
+    #[serde(with = "humantime_serde")]
+    pub forget_after: Duration,
+
+    /// Whether offenses also count against the IP address of the offender. This should be disabled in case many users
+    /// share an IP address, e.g. behind a NAT.
+    pub track_ips: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Disabled by default, so that the server does not write any files unless asked to
+    pub enabled: bool,
+    pub file: PathBuf,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    pub on_dimension_mismatch: DimensionMismatchPolicy,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournalConfig {
+    /// Disabled by default, as the journal grows with every painting and is never cleaned up
+    pub enabled: bool,
+    pub file: PathBuf,
+}
+
+/// What to do in case the snapshot on disk has different dimensions than the configured canvas
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DimensionMismatchPolicy {
+    /// Restore the part of the snapshot that fits onto the canvas, the rest of the canvas stays empty
+    #[default]
+    Crop,
+
+    /// Start with an empty canvas. The old snapshot and the journal are moved aside, so that they are not overwritten.
+    Discard,
+
+    /// Refuse to start
+    Fail,
+}
+
+/// Policy deciding which user gets the next slot, see [`crate::ascii_server::scheduling_policy`]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedulingPolicyKind {
+    /// Every user gets a slot in turn
+    #[default]
+    RoundRobin,
+
+    /// Users get slots proportional to their weight
+    WeightedFairShare,
+
+    /// Users that did not paint anything in their last slots are skipped in favor of active users
+    SkipIdle,
+}
+
+/// Penalty for a rule violation
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Penalty {
+    /// Only close the connection
+    Disconnect,
+
+    /// Keep the connection, but skip the given number of slots of the user
+    SkipSlots(usize),
+
+    /// Close the connection and reject the user and its IP address for the given duration
+    Ban(#[serde(with = "humantime_serde")] Duration),
+
+    /// Close the connection and reject the user and its IP address until the server is restarted
+    PermanentBan,
+}
+
+/// What to do in case a user logs in while already being logged in on another connection
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLoginPolicy {
+    /// Reject the new login, the existing connection keeps painting
+    #[default]
+    Reject,
+
+    /// Close the existing connection, the new connection takes over its place in the queue
+    Replace,
+}
+
+impl ProtectedRegion {
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    pub fn allows(&self, username: &str) -> bool {
+        self.allowed_users.iter().any(|allowed| allowed == username)
+    }
+}
+
+impl Default for CanvasConfig {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            protected_regions: Vec::new(),
+        }
+    }
+}
+
+impl Default for AsciiServerConfig {
+    fn default() -> Self {
+        Self {
+            listener_address: "[::]:1234".parse().expect("valid default socket address"),
+            max_connections_per_ip: 10,
+            max_input_line_length: 128,
+            users_file: PathBuf::from("./users.json"),
+            duplicate_login_policy: DuplicateLoginPolicy::default(),
+        }
+    }
+}
+
+impl Default for HttpServerConfig {
+    fn default() -> Self {
+        Self {
+            listener_addresses: vec!["[::]:3000".parse().expect("valid default socket address")],
+            admin_token: None,
+        }
+    }
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_pixels_per_slot: 5_000,
+            slot_duration: Duration::from_millis(500),
+            policy: SchedulingPolicyKind::default(),
+            parallel_slots: 1,
+            grace_period: Duration::from_millis(100),
+            discard_late_pixels: false,
+            weights: HashMap::new(),
+            idle_slots: 3,
+            adaptive: AdaptiveSlotConfig::default(),
+        }
+    }
+}
+
+impl Default for AdaptiveSlotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            round_duration: Duration::from_secs(10),
+            min_slot_duration: Duration::from_millis(100),
+            max_slot_duration: Duration::from_secs(2),
+            min_pixels_per_slot: 1_000,
+            max_pixels_per_slot: 20_000,
+        }
+    }
+}
+
+impl Default for ViolationConfig {
+    fn default() -> Self {
+        Self {
+            penalties: vec![
+                Penalty::SkipSlots(3),
+                Penalty::Ban(Duration::from_secs(60)),
+                Penalty::Ban(Duration::from_secs(10 * 60)),
+            ],
+            forget_after: Duration::from_secs(10 * 60),
+            track_ips: true,
+        }
+    }
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file: PathBuf::from("./canvas.snapshot"),
+            interval: Duration::from_secs(60),
+            on_dimension_mismatch: DimensionMismatchPolicy::default(),
+        }
+    }
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file: PathBuf::from("./paint.journal"),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file (if any was given), applies the overrides from the command line and validates the result.
+    pub fn load(args: &Args) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            Some(config_file) => Self::from_file(config_file)?,
+            None => Self::default(),
+        };
+
+        config.apply_overrides(args);
+        config.validate().context("Invalid configuration")?;
+
+        Ok(config)
+    }
+
+    fn from_file(config_file: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(config_file)
+            .with_context(|| format!("Failed to read config file {}", config_file.display()))?;
+
+        toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {}", config_file.display()))
+    }
+
+    fn apply_overrides(&mut self, args: &Args) {
+        if let Some(width) = args.width {
+            self.canvas.width = width;
+        }
+        if let Some(height) = args.height {
+            self.canvas.height = height;
+        }
+        if let Some(listener_address) = args.ascii_listener_address {
+            self.ascii_server.listener_address = listener_address;
+        }
+        if !args.http_listener_address.is_empty() {
+            self.http_server.listener_addresses = args.http_listener_address.clone();
+        }
+        if let Some(max_connections_per_ip) = args.max_connections_per_ip {
+            self.ascii_server.max_connections_per_ip = max_connections_per_ip;
+        }
+        if let Some(max_input_line_length) = args.max_input_line_length {
+            self.ascii_server.max_input_line_length = max_input_line_length;
+        }
+        if let Some(users_file) = &args.users_file {
+            self.ascii_server.users_file = users_file.clone();
+        }
+        if let Some(max_pixels_per_slot) = args.max_pixels_per_slot {
+            self.scheduler.max_pixels_per_slot = max_pixels_per_slot;
+        }
+        if let Some(slot_duration) = args.slot_duration {
+            self.scheduler.slot_duration = slot_duration;
+        }
+        if let Some(policy) = args.scheduling_policy {
+            self.scheduler.policy = policy;
+        }
+        if let Some(admin_token) = &args.admin_token {
+            self.http_server.admin_token = Some(admin_token.clone());
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.canvas.width == 0 || self.canvas.height == 0 {
+            bail!(
+                "The canvas needs to be at least 1x1 pixels, but is configured as {}x{}",
+                self.canvas.width,
+                self.canvas.height
+            );
+        }
+        for region in &self.canvas.protected_regions {
+            if region.width == 0 || region.height == 0 {
+                bail!(
+                    "The protected region {:?} needs to be at least 1x1 pixels",
+                    region.name
+                );
+            }
+            if region.x as u32 + region.width as u32 > self.canvas.width as u32
+                || region.y as u32 + region.height as u32 > self.canvas.height as u32
+            {
+                bail!(
+                    "The protected region {:?} is not within the {}x{} canvas",
+                    region.name,
+                    self.canvas.width,
+                    self.canvas.height
+                );
+            }
+        }
+        if self.http_server.listener_addresses.is_empty() {
+            bail!("http_server.listener_addresses needs to contain at least one address");
+        }
+        if self.ascii_server.max_connections_per_ip == 0 {
+            bail!("ascii_server.max_connections_per_ip needs to be at least 1, otherwise no one can connect");
+        }
+        if self.ascii_server.max_input_line_length < MIN_INPUT_LINE_LENGTH {
+            bail!(
+                "ascii_server.max_input_line_length is {}, but needs to be at least {MIN_INPUT_LINE_LENGTH} so that every request can be sent",
+                self.ascii_server.max_input_line_length
+            );
+        }
+        if self.scheduler.max_pixels_per_slot == 0 {
+            bail!(
+                "scheduler.max_pixels_per_slot needs to be at least 1, otherwise no one can paint"
+            );
+        }
+        if self.scheduler.slot_duration.is_zero() {
+            bail!("scheduler.slot_duration needs to be greater than zero");
+        }
+        let adaptive = &self.scheduler.adaptive;
+        if adaptive.enabled {
+            if adaptive.round_duration.is_zero() || adaptive.min_slot_duration.is_zero() {
+                bail!("scheduler.adaptive.round_duration and scheduler.adaptive.min_slot_duration need to be greater than zero");
+            }
+            if adaptive.min_slot_duration > adaptive.max_slot_duration {
+                bail!("scheduler.adaptive.min_slot_duration can not be greater than scheduler.adaptive.max_slot_duration");
+            }
+            if adaptive.min_pixels_per_slot == 0 {
+                bail!("scheduler.adaptive.min_pixels_per_slot needs to be at least 1, otherwise no one can paint");
+            }
+            if adaptive.min_pixels_per_slot > adaptive.max_pixels_per_slot {
+                bail!("scheduler.adaptive.min_pixels_per_slot can not be greater than scheduler.adaptive.max_pixels_per_slot");
+            }
+        }
+        // Connections don't process slot events during the grace period. As long as it is not longer than a slot, at most
+        // the start and end of the next slot of the same user are queued meanwhile.
+        let min_slot_duration = if adaptive.enabled {
+            adaptive.min_slot_duration
+        } else {
+            self.scheduler.slot_duration
+        };
+        if self.scheduler.grace_period > min_slot_duration {
+            bail!(
+                "scheduler.grace_period ({:?}) can not be longer than the shortest slot ({min_slot_duration:?})",
+                self.scheduler.grace_period
+            );
+        }
+        if let Some((username, _)) = self
+            .scheduler
+            .weights
+            .iter()
+            .find(|(_, weight)| **weight == 0)
+        {
+            bail!("scheduler.weights of user {username:?} needs to be at least 1");
+        }
+        if self.scheduler.parallel_slots == 0 {
+            bail!("scheduler.parallel_slots needs to be at least 1, otherwise no one can paint");
+        }
+        if self.scheduler.idle_slots == 0 {
+            bail!("scheduler.idle_slots needs to be at least 1");
+        }
+        if self
+            .http_server
+            .admin_token
+            .as_ref()
+            .is_some_and(|admin_token| admin_token.is_empty())
+        {
+            bail!(
+                "http_server.admin_token must not be empty, leave it out to disable the admin API"
+            );
+        }
+        if self.violations.penalties.is_empty() {
+            bail!("violations.penalties needs to contain at least one penalty, use [\"disconnect\"] to only close the connection");
+        }
+        if self.violations.penalties.contains(&Penalty::SkipSlots(0)) {
+            bail!("violations.penalties can not skip 0 slots, use \"disconnect\" to only close the connection");
+        }
+        if self.snapshot.enabled && self.snapshot.interval.is_zero() {
+            bail!("snapshot.interval needs to be greater than zero");
+        }
+
+        Ok(())
+    }
+}
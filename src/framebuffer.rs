@@ -1,18 +1,26 @@
+use anyhow::{ensure, Context};
 use colorgrad::Gradient;
-use prost::bytes::BufMut;
+use prost::bytes::{Buf, BufMut};
 
-use crate::proto::{web_socket_message::Payload, ClientPainting, ScreenSync, WebSocketMessage};
+use crate::proto::{ClientPainting, ScreenSync};
 
+#[derive(Clone)]
 pub struct FrameBuffer {
     width: u16,
     height: u16,
     pixels: Vec<u32>,
+
+    /// Incremented on every change of the pixels, so that derived data (e.g. encoded images) can be cached
+    version: u64,
 }
 
 pub struct PixelUpdate {
     pub x: u16,
     pub y: u16,
     pub rgba: u32,
+
+    /// Opacity the color is blended onto the existing pixel with, `255` overwrites the pixel
+    pub alpha: u8,
 }
 
 impl FrameBuffer {
@@ -23,6 +31,7 @@ impl FrameBuffer {
             width,
             height,
             pixels,
+            version: 0,
         }
     }
 
@@ -34,6 +43,10 @@ impl FrameBuffer {
         self.height
     }
 
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
     #[inline(always)]
     pub fn num_pixels(&self) -> usize {
         self.width as usize * self.height as usize
@@ -44,6 +57,23 @@ impl FrameBuffer {
         y as usize * self.width as usize + x as usize
     }
 
+    /// Returns all pixels that are not empty in the format of [`ClientPainting::painted`]
+    pub fn non_empty_pixels(&self) -> Vec<u8> {
+        let mut painted = Vec::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let rgba = self.pixels[self.index(x, y)];
+                if rgba != 0 {
+                    painted.put_u16(x);
+                    painted.put_u16(y);
+                    painted.put_u32(rgba);
+                }
+            }
+        }
+
+        painted
+    }
+
     /// Gets the rgba value for the given pixel if it exists
     ///
     /// The function returns [`None`] in case the pixel does not exist (because x or y is outside of screen)
@@ -56,27 +86,97 @@ impl FrameBuffer {
         }
     }
 
+    /// Paints the given pixels, blending them onto the existing pixels according to their alpha value.
+    ///
+    /// Pixels outside of the screen are skipped. The returned [`ClientPainting`] contains the resulting (blended) colors
+    /// of the painted pixels.
     #[inline(always)]
     pub fn set_multi(
         &mut self,
         client: impl Into<String>,
         painted: &[PixelUpdate],
-    ) -> WebSocketMessage {
+    ) -> ClientPainting {
+        self.version = self.version.wrapping_add(1);
+
         let mut painted_bytes = Vec::with_capacity(painted.len() * 8 /* bytes per pixel */);
-        for PixelUpdate { x, y, rgba } in painted {
+        for PixelUpdate { x, y, rgba, alpha } in painted {
+            if *x >= self.width || *y >= self.height {
+                continue;
+            }
+
             let index = self.index(*x, *y);
-            self.pixels[index] = *rgba;
+            let rgba = blend(self.pixels[index], *rgba, *alpha);
+            self.pixels[index] = rgba;
             painted_bytes.put_u16(*x);
             painted_bytes.put_u16(*y);
-            painted_bytes.put_u32(*rgba);
+            painted_bytes.put_u32(rgba);
+        }
+
+        ClientPainting {
+            client: client.into(),
+            painted: painted_bytes,
         }
+    }
+
+    /// Applies the pixels of a [`ClientPainting::painted`], e.g. when replaying the paint journal.
+    ///
+    /// Pixels outside of the screen are ignored, as the screen might have been resized in the meantime.
+    pub fn apply_painted(&mut self, mut painted: &[u8]) {
+        self.version = self.version.wrapping_add(1);
+
+        while painted.remaining() >= 8
+        /* bytes per pixel */
+        {
+            let x = painted.get_u16();
+            let y = painted.get_u16();
+            let rgba = painted.get_u32();
+
+            if x < self.width && y < self.height {
+                let index = self.index(x, y);
+                self.pixels[index] = rgba;
+            }
+        }
+    }
+
+    /// Copies the part of `other` that overlaps with this framebuffer (aligned at the top left corner)
+    pub fn copy_overlapping_from(&mut self, other: &FrameBuffer) {
+        self.version = self.version.wrapping_add(1);
+
+        let copy_width = self.width.min(other.width) as usize;
+        for y in 0..self.height.min(other.height) {
+            let dst = self.index(0, y);
+            let src = other.index(0, y);
+            self.pixels[dst..dst + copy_width]
+                .copy_from_slice(&other.pixels[src..src + copy_width]);
+        }
+    }
+
+    /// Returns the pixels of the given region as RGB bytes (row by row), scaling every pixel up by `scale`.
+    ///
+    /// The region needs to be within the screen.
+    pub fn region_rgb(&self, x: u16, y: u16, width: u16, height: u16, scale: u16) -> Vec<u8> {
+        let scale = scale as usize;
+        let mut rgb = Vec::with_capacity(
+            width as usize * height as usize * scale * scale * 3, /* bytes per pixel */
+        );
 
-        WebSocketMessage {
-            payload: Some(Payload::ClientPainting(ClientPainting {
-                client: client.into(),
-                painted: painted_bytes,
-            })),
+        for y in y..y + height {
+            let row_start = rgb.len();
+            for x in x..x + width {
+                let [_, r, g, b] = self.pixels[self.index(x, y)].to_be_bytes();
+                for _ in 0..scale {
+                    rgb.extend_from_slice(&[r, g, b]);
+                }
+            }
+
+            // Repeat the row we just produced for the remaining scaled rows
+            let row_end = rgb.len();
+            for _ in 1..scale {
+                rgb.extend_from_within(row_start..row_end);
+            }
         }
+
+        rgb
     }
 
     // pub fn fill_with_random_color(&mut self) {
@@ -93,6 +193,7 @@ impl FrameBuffer {
 
         let step_size = (end - start) / self.num_pixels() as f32;
 
+        self.version = self.version.wrapping_add(1);
         self.pixels = (0..self.num_pixels())
             .map(|pixel_idx| {
                 let [r, g, b, _] = gradient.at(start + pixel_idx as f32 * step_size).to_rgba8();
@@ -103,6 +204,28 @@ impl FrameBuffer {
     }
 }
 
+/// Blends `color` with the given alpha onto `existing` (both `0x00rrggbb`)
+#[inline(always)]
+fn blend(existing: u32, color: u32, alpha: u8) -> u32 {
+    match alpha {
+        u8::MAX => color,
+        0 => existing,
+        alpha => {
+            let alpha = alpha as u32;
+            let [_, existing @ ..] = existing.to_be_bytes();
+            let [_, color @ ..] = color.to_be_bytes();
+
+            let [r, g, b] = std::array::from_fn(|channel| {
+                // Rounded integer version of `color * alpha + existing * (1 - alpha)`
+                let blended =
+                    color[channel] as u32 * alpha + existing[channel] as u32 * (255 - alpha);
+                (blended + 127) / 255
+            });
+            r << 16 | g << 8 | b
+        }
+    }
+}
+
 impl From<&FrameBuffer> for ScreenSync {
     fn from(value: &FrameBuffer) -> Self {
         // TODO: Find more efficient way that works across all endianness
@@ -119,3 +242,86 @@ impl From<&FrameBuffer> for ScreenSync {
         }
     }
 }
+
+impl TryFrom<&ScreenSync> for FrameBuffer {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &ScreenSync) -> Result<Self, Self::Error> {
+        let width = u16::try_from(value.width)
+            .with_context(|| format!("The width {} is too big", value.width))?;
+        let height = u16::try_from(value.height)
+            .with_context(|| format!("The height {} is too big", value.height))?;
+
+        let mut framebuffer = Self::new(width, height);
+        ensure!(
+            value.pixels.len() == framebuffer.num_pixels() * 4,
+            "Expected {} bytes of pixel data for a {width}x{height} screen, but got {} bytes",
+            framebuffer.num_pixels() * 4,
+            value.pixels.len()
+        );
+
+        framebuffer.pixels = value
+            .pixels
+            .chunks_exact(4)
+            .map(|pixel| u32::from_le_bytes(pixel.try_into().expect("chunks have exactly 4 bytes")))
+            .collect();
+
+        Ok(framebuffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paint(fb: &mut FrameBuffer, rgba: u32, alpha: u8) -> u32 {
+        let painting = fb.set_multi(
+            "alice",
+            &[PixelUpdate {
+                x: 0,
+                y: 0,
+                rgba,
+                alpha,
+            }],
+        );
+        assert_eq!(painting.painted[4..], fb.get(0, 0).unwrap().to_be_bytes());
+        fb.get(0, 0).unwrap()
+    }
+
+    #[test]
+    fn blending() {
+        let mut fb = FrameBuffer::new(1, 1);
+        assert_eq!(paint(&mut fb, 0x204060, u8::MAX), 0x204060);
+        // Fully transparent pixels don't change anything
+        assert_eq!(paint(&mut fb, 0xffffff, 0), 0x204060);
+        // (0xff * 0x80 + 0x20 * 0x7f + 127) / 255 = 0x90 and so on
+        assert_eq!(paint(&mut fb, 0xffffff, 0x80), 0x90a0b0);
+        assert_eq!(paint(&mut fb, 0x000000, 0x80), 0x485058);
+        // Opaque pixels overwrite the existing color
+        assert_eq!(paint(&mut fb, 0x123456, u8::MAX), 0x123456);
+    }
+
+    #[test]
+    fn blending_rounds_to_the_nearest_value() {
+        assert_eq!(blend(0x000000, 0xffffff, 1), 0x010101);
+        assert_eq!(blend(0xffffff, 0x000000, 254), 0x010101);
+        assert_eq!(blend(0x000000, 0x010101, 127), 0x000000);
+        assert_eq!(blend(0x000000, 0x010101, 128), 0x010101);
+    }
+
+    #[test]
+    fn pixels_outside_of_the_screen_are_skipped() {
+        let mut fb = FrameBuffer::new(1, 1);
+        let painting = fb.set_multi(
+            "alice",
+            &[PixelUpdate {
+                x: 1,
+                y: 0,
+                rgba: 0xffffff,
+                alpha: u8::MAX,
+            }],
+        );
+        assert!(painting.painted.is_empty());
+        assert_eq!(fb.get(0, 0), Some(0));
+    }
+}
@@ -0,0 +1,228 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::AdminState;
+use crate::framebuffer::PixelUpdate;
+
+/// Name the changes made by admins are attributed to, e.g. in the journal and on the websockets
+const ADMIN_CLIENT: &str = "admin";
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Region {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+}
+
+#[derive(Deserialize)]
+pub struct WipeBody {
+    #[serde(flatten)]
+    region: Region,
+
+    /// Color as `rrggbb`, black in case none is given
+    color: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreBody {
+    #[serde(flatten)]
+    region: Region,
+
+    /// Unix timestamp in milliseconds the region is restored to
+    at: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RollbackBody {
+    username: String,
+
+    /// Unix timestamp in milliseconds, everything the user painted since then is rolled back
+    since: u64,
+}
+
+#[derive(Serialize)]
+pub struct PaintedBody {
+    num_pixels: usize,
+}
+
+/// Fills the given region with a single color, e.g. `{"x": 0, "y": 0, "width": 100, "height": 100, "color": "ff0000"}`
+pub async fn post_wipe(
+    state: State<AdminState>,
+    Json(body): Json<WipeBody>,
+) -> Result<Json<PaintedBody>, (StatusCode, String)> {
+    let rgba = match &body.color {
+        Some(color) if color.len() == 6 => u32::from_str_radix(color, 16).ok(),
+        Some(_) => None,
+        None => Some(0),
+    }
+    .ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid color {:?}, expected rrggbb", body.color),
+        )
+    })?;
+
+    let region = body.region;
+    check_region(&state, region).await?;
+
+    let painted: Vec<_> = region
+        .pixels()
+        .map(|(x, y)| PixelUpdate {
+            x,
+            y,
+            rgba,
+            alpha: u8::MAX,
+        })
+        .collect();
+    info!(?region, color = body.color, "Wiping region");
+
+    paint(&state, &painted).await
+}
+
+/// Restores the given region to how it looked like at the given point in time, reconstructed from the paint journal
+pub async fn post_restore(
+    state: State<AdminState>,
+    Json(body): Json<RestoreBody>,
+) -> Result<Json<PaintedBody>, (StatusCode, String)> {
+    let Some(journal) = &state.shared_state.journal else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "The paint journal is disabled, so no history is available".to_owned(),
+        ));
+    };
+
+    let region = body.region;
+    let (width, height) = check_region(&state, region).await?;
+    let framebuffer = journal
+        .canvas_at(width, height, body.at)
+        .await
+        .map_err(|err| {
+            error!(error = ?err, "Failed to reconstruct screen from journal");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to reconstruct screen from journal".to_owned(),
+            )
+        })?;
+    let painted: Vec<_> = region
+        .pixels()
+        .filter_map(|(x, y)| {
+            framebuffer.get(x, y).map(|rgba| PixelUpdate {
+                x,
+                y,
+                rgba,
+                alpha: u8::MAX,
+            })
+        })
+        .collect();
+    info!(?region, at = body.at, "Restoring region");
+
+    paint(&state, &painted).await
+}
+
+/// Reverts all pixels the given user painted since the given point in time, e.g. `{"username": "alice", "since": 0}`.
+///
+/// The pixels get the color they had before the user painted them. Pixels other users painted over afterwards are not
+/// changed.
+pub async fn post_rollback(
+    state: State<AdminState>,
+    Json(body): Json<RollbackBody>,
+) -> Result<Json<PaintedBody>, (StatusCode, String)> {
+    let Some(journal) = &state.shared_state.journal else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "The paint journal is disabled, so no history is available".to_owned(),
+        ));
+    };
+
+    let (width, height) = {
+        let fb = state.shared_state.framebuffer.read().await;
+        (fb.width(), fb.height())
+    };
+    let rolled_back = journal
+        .rollback_client(width, height, &body.username, body.since)
+        .await
+        .map_err(|err| {
+            error!(error = ?err, "Failed to determine pixels to roll back from journal");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to determine pixels to roll back from journal".to_owned(),
+            )
+        })?;
+
+    // Someone might have painted over the pixels while we were replaying the journal
+    let painted: Vec<_> = {
+        let fb = state.shared_state.framebuffer.read().await;
+        rolled_back
+            .into_iter()
+            .filter(|pixel| fb.get(pixel.x, pixel.y) == Some(pixel.painted_rgba))
+            .map(|pixel| PixelUpdate {
+                x: pixel.x,
+                y: pixel.y,
+                rgba: pixel.rgba,
+                alpha: u8::MAX,
+            })
+            .collect()
+    };
+    info!(
+        username = body.username,
+        since = body.since,
+        num_pixels = painted.len(),
+        "Rolling back user"
+    );
+
+    paint(&state, &painted).await
+}
+
+/// Checks that the region is within the canvas and returns the size of the canvas
+async fn check_region(
+    state: &AdminState,
+    region: Region,
+) -> Result<(u16, u16), (StatusCode, String)> {
+    let (width, height) = {
+        let fb = state.shared_state.framebuffer.read().await;
+        (fb.width(), fb.height())
+    };
+
+    if region.x as u32 + region.width as u32 > width as u32
+        || region.y as u32 + region.height as u32 > height as u32
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("The region {region:?} is not within the {width}x{height} canvas"),
+        ));
+    }
+
+    Ok((width, height))
+}
+
+/// Paints the pixels in the name of the admin, so that the change is journaled and shown on the websockets
+async fn paint(
+    state: &AdminState,
+    painted: &[PixelUpdate],
+) -> Result<Json<PaintedBody>, (StatusCode, String)> {
+    state
+        .shared_state
+        .paint(ADMIN_CLIENT, painted)
+        .await
+        .map_err(|err| {
+            error!(error = ?err, "Failed to paint pixels");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to paint pixels".to_owned(),
+            )
+        })?;
+
+    Ok(Json(PaintedBody {
+        num_pixels: painted.len(),
+    }))
+}
+
+impl Region {
+    fn pixels(self) -> impl Iterator<Item = (u16, u16)> {
+        (self.y..self.y + self.height)
+            .flat_map(move |y| (self.x..self.x + self.width).map(move |x| (x, y)))
+    }
+}
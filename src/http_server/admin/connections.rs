@@ -0,0 +1,36 @@
+use std::net::IpAddr;
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+
+use super::AdminState;
+use crate::ascii_server::user_scheduler::ConnectedUser;
+
+#[derive(Serialize)]
+pub struct Connections {
+    /// Logged in users
+    users: Vec<ConnectedUser>,
+
+    /// Open connections per IP address, including connections that are not logged in
+    ips: Vec<IpConnections>,
+}
+
+#[derive(Serialize)]
+pub struct IpConnections {
+    ip: IpAddr,
+    connections: usize,
+}
+
+/// Lists the logged in users as well as the number of open connections per IP address
+pub async fn get_connections(state: State<AdminState>) -> Json<Connections> {
+    let users = state.user_scheduler.connected_users().await;
+    let ips = state
+        .connections_per_ip
+        .read()
+        .await
+        .iter()
+        .map(|(&ip, &connections)| IpConnections { ip, connections })
+        .collect();
+
+    Json(Connections { users, ips })
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post, put},
+    Router,
+};
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock;
+
+use crate::{
+    app_state::AppState,
+    ascii_server::{user_scheduler::UserScheduler, violation_tracker::ViolationTracker},
+    http_server::admin::{
+        canvas::{post_restore, post_rollback, post_wipe},
+        connections::get_connections,
+        moderation::{delete_ip_ban, delete_user_ban, post_kick_user, put_ip_ban, put_user_ban},
+        pause::{get_pause, put_pause},
+        scheduling_policy::{get_scheduling_policy, put_scheduling_policy},
+        violations::get_violations,
+    },
+};
+
+mod canvas;
+mod connections;
+mod moderation;
+mod pause;
+mod scheduling_policy;
+mod violations;
+
+/// State of the admin API, which needs access to the internals of the ASCII server
+#[derive(Clone)]
+pub struct AdminState {
+    pub shared_state: Arc<AppState>,
+    pub user_scheduler: Arc<UserScheduler>,
+    pub violation_tracker: Arc<ViolationTracker>,
+    pub connections_per_ip: Arc<RwLock<HashMap<IpAddr, usize>>>,
+}
+
+/// Routes of the admin API, all of them require the `Authorization: Bearer <admin_token>` header
+pub fn admin_router<S>(admin_state: AdminState, admin_token: &str) -> Router<S> {
+    Router::new()
+        .route(
+            "/scheduling-policy",
+            get(get_scheduling_policy).put(put_scheduling_policy),
+        )
+        .route("/pause", get(get_pause).put(put_pause))
+        .route("/violations", get(get_violations))
+        .route("/connections", get(get_connections))
+        .route("/users/{username}/kick", post(post_kick_user))
+        .route(
+            "/bans/users/{username}",
+            put(put_user_ban).delete(delete_user_ban),
+        )
+        .route("/bans/ips/{ip}", put(put_ip_ban).delete(delete_ip_ban))
+        .route("/canvas/wipe", post(post_wipe))
+        .route("/canvas/restore", post(post_restore))
+        .route("/canvas/rollback", post(post_rollback))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(admin_token),
+            require_admin_token,
+        ))
+        .with_state(admin_state)
+}
+
+async fn require_admin_token(
+    State(admin_token): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // Comparing in constant time, so that the token can not be guessed byte by byte from the response times
+        .is_some_and(|token| token.as_bytes().ct_eq(admin_token.as_bytes()).into());
+
+    if !authorized {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "The admin API requires a valid \"Authorization: Bearer <admin token>\" header",
+        ));
+    }
+
+    Ok(next.run(request).await)
+}
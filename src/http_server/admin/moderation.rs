@@ -0,0 +1,118 @@
+use std::{net::IpAddr, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use super::AdminState;
+use crate::ascii_server::{user_scheduler::KickOutcome, violation_tracker::Offender};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BanBody {
+    /// Duration of the ban, e.g. `10m`. The ban is permanent in case no duration is given.
+    #[serde(default, with = "humantime_serde")]
+    duration: Option<Duration>,
+}
+
+#[derive(Serialize)]
+pub struct KickedBody {
+    /// Names of the users whose connections were closed
+    kicked: Vec<String>,
+
+    /// Names of the users whose connections could not be told to close, as they were already closing
+    not_delivered: Vec<String>,
+}
+
+impl From<KickOutcome> for KickedBody {
+    fn from(outcome: KickOutcome) -> Self {
+        Self {
+            kicked: outcome.kicked,
+            not_delivered: outcome.not_delivered,
+        }
+    }
+}
+
+/// Closes the connection of the given user
+pub async fn post_kick_user(
+    state: State<AdminState>,
+    Path(username): Path<String>,
+) -> Result<Json<KickedBody>, (StatusCode, String)> {
+    let outcome = state
+        .user_scheduler
+        .kick(|connected, _| connected == username)
+        .await;
+    if outcome.kicked.is_empty() {
+        return Err(if outcome.not_delivered.is_empty() {
+            (
+                StatusCode::NOT_FOUND,
+                format!("The user {username:?} is not logged in"),
+            )
+        } else {
+            (
+                StatusCode::CONFLICT,
+                format!("The connection of user {username:?} could not be kicked, as it is already closing"),
+            )
+        });
+    }
+
+    Ok(Json(outcome.into()))
+}
+
+/// Bans the given user and closes its connection, e.g. `{"duration": "10m"}` or `{}` for a permanent ban
+pub async fn put_user_ban(
+    state: State<AdminState>,
+    Path(username): Path<String>,
+    Json(body): Json<BanBody>,
+) -> Json<KickedBody> {
+    state
+        .violation_tracker
+        .ban(Offender::User(username.clone()), body.duration)
+        .await;
+    let outcome = state
+        .user_scheduler
+        .kick(|connected, _| connected == username)
+        .await;
+
+    Json(outcome.into())
+}
+
+/// Bans the given IP address and closes the connections of all users logged in from it
+pub async fn put_ip_ban(
+    state: State<AdminState>,
+    Path(ip): Path<IpAddr>,
+    Json(body): Json<BanBody>,
+) -> Json<KickedBody> {
+    // Connections are tracked by their canonical address, see `AsciiServer::handle_connection`
+    let ip = ip.to_canonical();
+    state
+        .violation_tracker
+        .ban(Offender::Ip(ip), body.duration)
+        .await;
+    let outcome = state
+        .user_scheduler
+        .kick(|_, connected_ip| connected_ip == ip)
+        .await;
+
+    Json(outcome.into())
+}
+
+pub async fn delete_user_ban(state: State<AdminState>, Path(username): Path<String>) -> StatusCode {
+    unban(&state, Offender::User(username)).await
+}
+
+pub async fn delete_ip_ban(state: State<AdminState>, Path(ip): Path<IpAddr>) -> StatusCode {
+    unban(&state, Offender::Ip(ip.to_canonical())).await
+}
+
+/// Lifts the ban and forgets about all previous offenses
+async fn unban(state: &AdminState, offender: Offender) -> StatusCode {
+    if state.violation_tracker.unban(&offender).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
@@ -0,0 +1,22 @@
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+
+use super::AdminState;
+
+#[derive(Deserialize, Serialize)]
+pub struct PauseBody {
+    paused: bool,
+}
+
+pub async fn get_pause(state: State<AdminState>) -> Json<PauseBody> {
+    Json(PauseBody {
+        paused: state.user_scheduler.paused(),
+    })
+}
+
+/// Pauses or resumes the painting, e.g. `{"paused": true}`. The change takes effect with the next slot.
+pub async fn put_pause(state: State<AdminState>, Json(body): Json<PauseBody>) -> Json<PauseBody> {
+    state.user_scheduler.set_paused(body.paused);
+
+    Json(body)
+}
@@ -0,0 +1,26 @@
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+
+use super::AdminState;
+use crate::config::SchedulingPolicyKind;
+
+#[derive(Deserialize, Serialize)]
+pub struct SchedulingPolicyBody {
+    policy: SchedulingPolicyKind,
+}
+
+pub async fn get_scheduling_policy(state: State<AdminState>) -> Json<SchedulingPolicyBody> {
+    Json(SchedulingPolicyBody {
+        policy: state.user_scheduler.policy().await,
+    })
+}
+
+/// Switches the scheduling policy at runtime, e.g. `{"policy": "weighted_fair_share"}`
+pub async fn put_scheduling_policy(
+    state: State<AdminState>,
+    Json(body): Json<SchedulingPolicyBody>,
+) -> Json<SchedulingPolicyBody> {
+    state.user_scheduler.set_policy(body.policy).await;
+
+    Json(body)
+}
@@ -0,0 +1,9 @@
+use axum::{extract::State, Json};
+
+use super::AdminState;
+use crate::ascii_server::violation_tracker::ViolationEntry;
+
+/// Lists all users and IP addresses with recent rule violations or an active ban
+pub async fn get_violations(state: State<AdminState>) -> Json<Vec<ViolationEntry>> {
+    Json(state.violation_tracker.entries().await)
+}
@@ -0,0 +1,253 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::error;
+
+use crate::{app_state::AppState, png_export::encode_png};
+
+/// Maximum width and height of the produced image (after scaling)
+const MAX_IMAGE_SIZE: u32 = 4096;
+const MAX_SCALE: u16 = 16;
+
+/// Number of different regions we cache images for
+const MAX_CACHED_IMAGES: usize = 16;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+pub struct PngQuery {
+    x: Option<u16>,
+    y: Option<u16>,
+    w: Option<u16>,
+    h: Option<u16>,
+    scale: Option<u16>,
+}
+
+/// Caches the encoded images as long as the screen does not change, so that e.g. many viewers fetching the image
+/// within the same slot don't cause it to be re-encoded every time.
+#[derive(Default)]
+pub struct PngCache {
+    /// Key: Framebuffer version the images were encoded for
+    images: Mutex<(u64, HashMap<PngQuery, Bytes>)>,
+}
+
+pub async fn get_current_screen_png(
+    state: State<Arc<AppState>>,
+    Query(query): Query<PngQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let (rgb, width, height, version) = {
+        let fb = state.framebuffer.read().await;
+
+        let x = query.x.unwrap_or(0);
+        let y = query.y.unwrap_or(0);
+        let w = query.w.unwrap_or(fb.width().saturating_sub(x));
+        let h = query.h.unwrap_or(fb.height().saturating_sub(y));
+        let scale = query.scale.unwrap_or(1);
+
+        if w == 0
+            || h == 0
+            || x as u32 + w as u32 > fb.width() as u32
+            || y as u32 + h as u32 > fb.height() as u32
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "The region {w}x{h} at {x},{y} needs to be non-empty and within the screen of size {}x{}",
+                    fb.width(),
+                    fb.height()
+                ),
+            ));
+        }
+        let (width, height) = (w as u32 * scale as u32, h as u32 * scale as u32);
+        if !(1..=MAX_SCALE).contains(&scale) || width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "The scale needs to be between 1 and {MAX_SCALE} and the resulting image can be at most {MAX_IMAGE_SIZE}x{MAX_IMAGE_SIZE} pixels"
+                ),
+            ));
+        }
+
+        let mut cache = state.png_cache.images.lock().await;
+        if cache.0 == fb.version() {
+            if let Some(png) = cache.1.get(&query) {
+                return Ok(png_response(png.clone()));
+            }
+        } else {
+            *cache = (fb.version(), HashMap::new());
+        }
+
+        (
+            fb.region_rgb(x, y, w, h, scale),
+            width,
+            height,
+            fb.version(),
+        )
+    };
+
+    // Encoding can take a while, so we put it on the blocking threadpool
+    let png = tokio::task::spawn_blocking(move || encode_png(&rgb, width, height))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result)
+        .map_err(|err| {
+            error!(error = ?err, "Failed to encode screen as PNG");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to encode screen as PNG".to_owned(),
+            )
+        })?;
+    let png = Bytes::from(png);
+
+    let mut cache = state.png_cache.images.lock().await;
+    // The screen might have changed while we were encoding, in that case the image is already outdated
+    if cache.0 == version {
+        if cache.1.len() >= MAX_CACHED_IMAGES {
+            cache.1.clear();
+        }
+        cache.1.insert(query, png.clone());
+    }
+
+    Ok(png_response(png))
+}
+
+fn png_response(png: Bytes) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "image/png")], png)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::*;
+    use crate::{config::Config, framebuffer::PixelUpdate};
+
+    /// 4x3 canvas with a red pixel at (3, 2)
+    fn state() -> Arc<AppState> {
+        let mut config = Config::default();
+        config.canvas.width = 4;
+        config.canvas.height = 3;
+        config.snapshot.enabled = false;
+        config.journal.enabled = false;
+        let (ws_message_tx, _) = mpsc::channel(1);
+        let (_, compressed_ws_message_rx) = broadcast::channel(1);
+        let state = AppState::new(&config, ws_message_tx, compressed_ws_message_rx)
+            .expect("app state without snapshot and journal can be created");
+        paint(&state, 3, 2, 0xff0000);
+
+        Arc::new(state)
+    }
+
+    fn paint(state: &AppState, x: u16, y: u16, rgba: u32) {
+        state.framebuffer.try_write().unwrap().set_multi(
+            "alice",
+            &[PixelUpdate {
+                x,
+                y,
+                rgba,
+                alpha: u8::MAX,
+            }],
+        );
+    }
+
+    async fn get_png(state: &Arc<AppState>, query: &str) -> Result<Bytes, StatusCode> {
+        let uri = format!("/api/current-screen.png?{query}").parse().unwrap();
+        let query = Query::try_from_uri(&uri).unwrap();
+        match get_current_screen_png(State(state.clone()), query).await {
+            Ok(response) => Ok(axum::body::to_bytes(
+                response.into_response().into_body(),
+                usize::MAX,
+            )
+            .await
+            .unwrap()),
+            Err((status, _)) => Err(status),
+        }
+    }
+
+    /// Returns the size and the RGB pixels of the given PNG
+    fn decode(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = png::Decoder::new(png).read_info().unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgb).unwrap();
+        rgb.truncate(info.buffer_size());
+
+        (info.width, info.height, rgb)
+    }
+
+    #[tokio::test]
+    async fn region_defaults_to_the_rest_of_the_screen() {
+        let state = state();
+
+        let (width, height, rgb) = decode(&get_png(&state, "").await.unwrap());
+        assert_eq!((width, height), (4, 3));
+        assert_eq!(rgb[rgb.len() - 3..], [0xff, 0, 0]);
+
+        let (width, height, rgb) = decode(&get_png(&state, "x=3&y=1").await.unwrap());
+        assert_eq!((width, height), (1, 2));
+        assert_eq!(rgb, [0, 0, 0, 0xff, 0, 0]);
+
+        let (width, height, rgb) =
+            decode(&get_png(&state, "x=2&y=2&w=2&h=1&scale=2").await.unwrap());
+        assert_eq!((width, height), (4, 2));
+        let row = [0, 0, 0, 0, 0, 0, 0xff, 0, 0, 0xff, 0, 0];
+        assert_eq!(rgb, [row, row].concat());
+    }
+
+    #[tokio::test]
+    async fn regions_outside_of_the_screen_are_rejected() {
+        let state = state();
+
+        for query in [
+            // Empty regions
+            "x=4",
+            "y=3",
+            "w=0",
+            "h=0",
+            // Regions reaching over the edge
+            "x=1&w=4",
+            "y=1&h=3",
+            "x=65535&w=65535",
+            // Invalid scales
+            "scale=0",
+            "scale=17",
+        ] {
+            assert_eq!(
+                get_png(&state, query).await.map(|_| ()),
+                Err(StatusCode::BAD_REQUEST),
+                "{query}"
+            );
+        }
+
+        let (width, height, _) = decode(&get_png(&state, "scale=16").await.unwrap());
+        assert_eq!((width, height), (64, 48));
+    }
+
+    #[tokio::test]
+    async fn cached_images_are_invalidated_when_the_screen_changes() {
+        let state = state();
+
+        let first = get_png(&state, "").await.unwrap();
+        let cached = get_png(&state, "").await.unwrap();
+        // The cached image is handed out without copying it
+        assert_eq!(first.as_ptr(), cached.as_ptr());
+
+        paint(&state, 0, 0, 0x00ff00);
+        let changed = get_png(&state, "").await.unwrap();
+        assert_ne!(changed, first);
+        let (_, _, rgb) = decode(&changed);
+        assert_eq!(rgb[..3], [0, 0xff, 0]);
+
+        // Other regions are cached separately
+        let region = get_png(&state, "w=1&h=1").await.unwrap();
+        assert_ne!(region, changed);
+        assert_eq!(
+            get_png(&state, "").await.unwrap().as_ptr(),
+            changed.as_ptr()
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use prost::Message;
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{app_state::AppState, proto::ScreenSync};
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    /// Unix timestamp in milliseconds
+    at: u64,
+}
+
+/// Returns the screen as it looked like at the given point in time, reconstructed from the paint journal
+pub async fn get_history(
+    state: State<Arc<AppState>>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let Some(journal) = &state.journal else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "The paint journal is disabled, so no history is available".to_owned(),
+        ));
+    };
+
+    let (width, height) = {
+        let fb = state.framebuffer.read().await;
+        (fb.width(), fb.height())
+    };
+    let framebuffer = journal
+        .canvas_at(width, height, query.at)
+        .await
+        .map_err(|err| {
+            error!(error = ?err, "Failed to reconstruct screen from journal");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to reconstruct screen from journal".to_owned(),
+            )
+        })?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-protobuf")],
+        Bytes::from(ScreenSync::from(&framebuffer).encode_to_vec()),
+    ))
+}
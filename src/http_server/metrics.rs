@@ -0,0 +1,21 @@
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use prometheus::TEXT_FORMAT;
+use tracing::error;
+
+use crate::metrics;
+
+/// Returns all metrics in the Prometheus text format
+pub async fn get_metrics() -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let metrics = metrics::render().map_err(|err| {
+        error!(error = ?err, "Failed to render metrics");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to render metrics",
+        )
+    })?;
+
+    Ok(([(header::CONTENT_TYPE, TEXT_FORMAT)], metrics))
+}
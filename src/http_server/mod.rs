@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{future::IntoFuture, sync::Arc};
 
 use anyhow::Context;
 use axum::{
@@ -6,6 +6,7 @@ use axum::{
     routing::{get, get_service},
     Router,
 };
+use futures::future::try_join_all;
 use tokio::net::TcpListener;
 use tower_http::{
     cors::CorsLayer,
@@ -15,36 +16,68 @@ use tracing::info;
 
 use crate::{
     app_state::AppState,
+    config::HttpServerConfig,
     http_server::{
-        current_screen::get_current_screen, current_screen_size::get_current_screen_size,
+        admin::{admin_router, AdminState},
+        current_screen::get_current_screen,
+        current_screen_png::get_current_screen_png,
+        current_screen_size::get_current_screen_size,
+        history::get_history,
+        metrics::get_metrics,
+        protected_regions::get_protected_regions,
         websocket::handle_websocket,
     },
 };
 
+pub mod admin;
 mod current_screen;
+pub mod current_screen_png;
 mod current_screen_size;
+mod history;
+mod metrics;
+mod protected_regions;
 pub mod websocket;
 
 pub async fn run_http_server(
     shared_state: Arc<AppState>,
-    listener_address: &str,
+    admin_state: AdminState,
+    config: &HttpServerConfig,
 ) -> anyhow::Result<()> {
-    let app = build_router(shared_state);
+    let app = build_router(shared_state, admin_state, config.admin_token.as_deref());
 
-    let listener = TcpListener::bind("0.0.0.0:3000")
-        .await
-        .with_context(|| format!("Failed to bind to web listener address {listener_address}"))?;
+    // Bind all addresses before serving, so that we fail early in case any of them is not usable
+    let mut listeners = Vec::with_capacity(config.listener_addresses.len());
+    for listener_address in &config.listener_addresses {
+        let listener = TcpListener::bind(listener_address).await.with_context(|| {
+            format!("Failed to bind to web listener address {listener_address}")
+        })?;
 
-    info!("Starting HTTP server at http://localhost:3000");
-    axum::serve(listener, app)
-        .await
-        .context("Failed to start server")?;
+        // In case port 0 was configured, this is the actual port the OS picked
+        let local_addr = listener
+            .local_addr()
+            .context("Failed to get local address of HTTP listener")?;
+        info!("Starting HTTP server at http://{local_addr}");
+
+        listeners.push(listener);
+    }
+
+    try_join_all(
+        listeners
+            .into_iter()
+            .map(|listener| axum::serve(listener, app.clone()).into_future()),
+    )
+    .await
+    .context("Failed to start server")?;
 
     Ok(())
 }
 
-fn build_router(shared_state: Arc<AppState>) -> Router {
-    Router::new()
+fn build_router(
+    shared_state: Arc<AppState>,
+    admin_state: AdminState,
+    admin_token: Option<&str>,
+) -> Router {
+    let router = Router::new()
         .route_service("/", get_service(ServeFile::new("./web/static/index.html")))
         .route(
             "/ws",
@@ -55,9 +88,23 @@ fn build_router(shared_state: Arc<AppState>) -> Router {
             ),
         )
         .route("/api/current-screen", get(get_current_screen))
+        .route("/api/current-screen.png", get(get_current_screen_png))
         .route("/api/current-screen-size", get(get_current_screen_size))
+        .route("/api/history", get(get_history))
+        .route("/api/protected-regions", get(get_protected_regions))
+        .route("/metrics", get(get_metrics))
         .nest_service("/static", get_service(ServeDir::new("./web/static")))
         // TODO: Try to restrict
-        .layer(CorsLayer::permissive())
-        .with_state(shared_state)
+        // Only covers the routes above, so that other websites can not use the admin API from the browser of an admin
+        .layer(CorsLayer::permissive());
+
+    let router = match admin_token {
+        Some(admin_token) => router.nest("/admin", admin_router(admin_state, admin_token)),
+        None => {
+            info!("No admin token configured, the admin API is disabled");
+            router
+        }
+    };
+
+    router.with_state(shared_state)
 }
@@ -0,0 +1,10 @@
+use std::sync::Arc;
+
+use axum::{extract::State, Json};
+
+use crate::{app_state::AppState, config::ProtectedRegion};
+
+/// Lists the protected regions of the canvas, so that the web UI can outline them
+pub async fn get_protected_regions(state: State<Arc<AppState>>) -> Json<Vec<ProtectedRegion>> {
+    Json(state.protected_regions.clone())
+}
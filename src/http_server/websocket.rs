@@ -15,6 +15,7 @@ use zstd::DEFAULT_COMPRESSION_LEVEL;
 
 use crate::{
     app_state::AppState,
+    metrics,
     proto::{web_socket_message::Payload, WebSocketClosedBecauseOfLag, WebSocketMessage},
 };
 
@@ -22,13 +23,18 @@ const ZSTD_COMPRESSION_LEVEL: i32 = DEFAULT_COMPRESSION_LEVEL;
 
 pub async fn handle_websocket(mut ws: WebSocket, state: State<Arc<AppState>>) {
     info!("Websocket connected");
+    metrics::WEBSOCKET_VIEWERS.inc();
 
     let mut rx = state.compressed_ws_message_tx.resubscribe();
 
     loop {
         let compressed_ws_message = rx.recv().await;
         let compressed_ws_message = match compressed_ws_message {
-            Ok(compressed_ws_message) => compressed_ws_message,
+            Ok(compressed_ws_message) => {
+                // The websocket lags behind in case messages pile up, see the capacity of the channel
+                metrics::WEBSOCKET_QUEUE_LENGTH.observe(rx.len() as f64);
+                compressed_ws_message
+            }
             Err(RecvError::Closed) => {
                 // Server is shutting down
                 break;
@@ -38,6 +44,7 @@ pub async fn handle_websocket(mut ws: WebSocket, state: State<Arc<AppState>>) {
                     lag,
                     "The websocket loop has too much lag, closing connection"
                 );
+                metrics::WEBSOCKET_LAG_CLOSES.inc();
 
                 let compressed_ws_message = match web_socket_closed_because_of_lag_message(lag) {
                     Ok(compressed_ws_message) => compressed_ws_message,
@@ -71,6 +78,7 @@ pub async fn handle_websocket(mut ws: WebSocket, state: State<Arc<AppState>>) {
         }
     }
 
+    metrics::WEBSOCKET_VIEWERS.dec();
     info!("Websocket closed");
 }
 
@@ -137,6 +145,11 @@ fn compress_message(ws_message: &WebSocketMessage) -> anyhow::Result<Vec<u8>> {
         })?;
     let compression_duration = start.elapsed();
 
+    metrics::WEBSOCKET_COMPRESSION_SECONDS
+        .observe((encoding_duration + compression_duration).as_secs_f64());
+    metrics::WEBSOCKET_COMPRESSION_RATIO
+        .observe(uncompressed_bytes.len() as f64 / compressed_bytes.len() as f64);
+
     trace!(
         compression_ratio = uncompressed_bytes.len() / compressed_bytes.len(),
         compressed_bytes = compressed_bytes.len(),
@@ -0,0 +1,718 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use prost::{bytes::Buf, Message};
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    sync::{mpsc, oneshot, Semaphore},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    framebuffer::FrameBuffer,
+    metrics,
+    proto::{ClientPainting, JournalEntry},
+};
+
+/// Append-only journal of everything that was painted.
+///
+/// The journal consists of length-delimited [`JournalEntry`] protobuf messages. It allows us to reconstruct the canvas
+/// at any point in time and to recover the changes since the last snapshot after a crash.
+pub struct Journal {
+    file: PathBuf,
+
+    /// Sends entries to the writer task, which writes them in the order they were appended
+    writer_tx: mpsc::UnboundedSender<WriterMessage>,
+
+    /// Canvases reconstructed by [`Journal::canvas_at`], so that we don't need to replay the whole journal every time
+    checkpoints: Arc<StdMutex<VecDeque<Checkpoint>>>,
+
+    /// Limits the number of concurrent replays, as each of them reads the journal and holds a whole canvas in memory
+    replay_permits: Semaphore,
+}
+
+/// Maximum number of [`Checkpoint`]s we keep, each of them holds a whole canvas in memory
+const MAX_CHECKPOINTS: usize = 8;
+
+/// Maximum number of journal replays running at the same time, further requests need to wait
+const MAX_CONCURRENT_REPLAYS: usize = 2;
+
+/// Every journal entry is a single slot of a single user, so it can not get bigger than a few megabytes. Anything
+/// bigger is a corrupt length.
+const MAX_ENTRY_LEN: u64 = 64 * 1024 * 1024;
+
+/// Name the canvas a journal starts with is attributed to, see [`Journal::recover`]
+const BASE_CLIENT: &str = "base";
+
+/// Maximum number of pixels per entry the canvas a journal starts with is split into, so that every entry stays well
+/// below [`MAX_ENTRY_LEN`]
+const MAX_BASE_PIXELS_PER_ENTRY: usize = 1024 * 1024;
+
+enum WriterMessage {
+    Entry(JournalEntry),
+
+    /// Answered once all entries sent before are written to disk
+    Flush(oneshot::Sender<std::io::Result<()>>),
+}
+
+/// The canvas after replaying the journal up to a given byte offset
+struct Checkpoint {
+    /// All entries before `offset` were painted at or before this point in time
+    at_ms: u64,
+
+    /// Byte offset in the journal the replay can be continued from
+    offset: u64,
+
+    /// Timestamp of the last entry before `offset`, see [`JournalReader`]
+    last_painted_at_ms: u64,
+
+    /// The next entry was painted after `at_ms`, so no entries painted at or before `at_ms` can be appended anymore
+    complete: bool,
+    framebuffer: FrameBuffer,
+}
+
+impl Journal {
+    /// Replays all entries painted at or after `since_ms` onto the framebuffer and opens the journal for appending.
+    ///
+    /// This recovers all changes made after the last snapshot was taken. In case we crashed while writing the last
+    /// entry, the incomplete entry is cut off, so that the entries appended from now on can be read again.
+    ///
+    /// A new journal starts with the pixels the canvas already has (e.g. restored from a snapshot taken while the
+    /// journal was disabled), so that replaying the journal onto an empty canvas always reconstructs the whole canvas.
+    ///
+    /// Spawns the task writing the journal, so this needs to be called within a Tokio runtime.
+    pub fn recover(
+        file: &Path,
+        framebuffer: &mut FrameBuffer,
+        since_ms: u64,
+    ) -> anyhow::Result<Self> {
+        let display_file = file.display();
+
+        let mut valid_len = 0;
+        if file.exists() {
+            let mut reader = JournalReader::open(file)?;
+            let mut replayed = 0;
+            for entry in reader.by_ref() {
+                let entry = entry.context("Failed to read journal")?;
+                if entry.painted_at_ms < since_ms {
+                    continue;
+                }
+
+                if let Some(painting) = entry.painting {
+                    framebuffer.apply_painted(&painting.painted);
+                    replayed += 1;
+                }
+            }
+
+            valid_len = reader.valid_len();
+            info!(file = %display_file, replayed, since_ms, "Recovered changes from journal");
+        }
+
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .with_context(|| format!("Failed to open journal file {display_file}"))?;
+
+        let len = journal
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of journal file {display_file}"))?
+            .len();
+        if len > valid_len {
+            warn!(
+                file = %display_file,
+                len,
+                valid_len,
+                "The journal ends with an incomplete entry, truncating it"
+            );
+            journal
+                .set_len(valid_len)
+                .with_context(|| format!("Failed to truncate journal file {display_file}"))?;
+        }
+
+        let (writer_tx, writer_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_writer(tokio::fs::File::from_std(journal), writer_rx));
+        let journal = Self {
+            file: file.to_owned(),
+            writer_tx,
+            checkpoints: Default::default(),
+            replay_permits: Semaphore::new(MAX_CONCURRENT_REPLAYS),
+        };
+
+        if valid_len == 0 {
+            let base = framebuffer.non_empty_pixels();
+            if !base.is_empty() {
+                info!(file = %display_file, num_pixels = base.len() / 8, "Recording the canvas the journal starts with");
+            }
+            for painted in base.chunks(MAX_BASE_PIXELS_PER_ENTRY * 8 /* bytes per pixel */) {
+                let painting = ClientPainting {
+                    client: BASE_CLIENT.to_owned(),
+                    painted: painted.to_vec(),
+                };
+                journal.append(since_ms.min(unix_millis_now()), &painting);
+            }
+        }
+
+        Ok(journal)
+    }
+
+    /// Reconstructs the canvas as it looked like at the given point in time.
+    ///
+    /// The replay continues from the latest checkpoint before `at_ms` (if any) and is stored as a checkpoint itself, so
+    /// repeated requests for the same or a later point in time don't need to replay the whole journal again.
+    pub async fn canvas_at(
+        &self,
+        width: u16,
+        height: u16,
+        at_ms: u64,
+    ) -> anyhow::Result<FrameBuffer> {
+        let _permit = self
+            .replay_permits
+            .acquire()
+            .await
+            .context("Failed to wait for other journal replays")?;
+        self.flush().await?;
+
+        let file = self.file.clone();
+        let checkpoints = self.checkpoints.clone();
+        // Replaying the journal can take a while, so we put it on the blocking threadpool
+        tokio::task::spawn_blocking(move || {
+            replay_from_checkpoint(&file, &checkpoints, width, height, at_ms)
+        })
+        .await
+        .context("Failed to join journal replay")?
+    }
+
+    /// Determines how to undo everything `client` painted at or after `since_ms`.
+    ///
+    /// Replays the journal (starting from the latest checkpoint before `since_ms`) and returns the pixels that were
+    /// last painted by the client, together with the color they had before. Pixels another client painted over
+    /// afterwards are left alone.
+    pub async fn rollback_client(
+        &self,
+        width: u16,
+        height: u16,
+        client: &str,
+        since_ms: u64,
+    ) -> anyhow::Result<Vec<RolledBackPixel>> {
+        let _permit = self
+            .replay_permits
+            .acquire()
+            .await
+            .context("Failed to wait for other journal replays")?;
+        self.flush().await?;
+
+        let file = self.file.clone();
+        let checkpoints = self.checkpoints.clone();
+        let client = client.to_owned();
+        // Replaying the journal can take a while, so we put it on the blocking threadpool
+        tokio::task::spawn_blocking(move || {
+            rollback_from_checkpoint(&file, &checkpoints, width, height, &client, since_ms)
+        })
+        .await
+        .context("Failed to join journal replay")?
+    }
+
+    /// Appends the painting to the journal without waiting for it to be written.
+    ///
+    /// Entries are written in the order they were appended, so callers can keep the journal in the same order as the
+    /// framebuffer by appending while holding its lock. Failing writes are logged and counted in
+    /// [`metrics::JOURNAL_WRITE_ERRORS`], as the pixels are already painted at that point.
+    pub fn append(&self, painted_at_ms: u64, painting: &ClientPainting) {
+        let entry = JournalEntry {
+            painted_at_ms,
+            painting: Some(painting.clone()),
+        };
+        if self.writer_tx.send(WriterMessage::Entry(entry)).is_err() {
+            error!("The journal writer stopped, the painting is not journaled");
+            metrics::JOURNAL_WRITE_ERRORS.inc();
+        }
+    }
+
+    /// Waits until all entries appended so far are written to disk
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.writer_tx
+            .send(WriterMessage::Flush(done_tx))
+            .ok()
+            .context("The journal writer stopped")?;
+        done_rx
+            .await
+            .context("The journal writer stopped")?
+            .context("Failed to flush journal")
+    }
+}
+
+/// Writes the entries sent by [`Journal::append`] until the [`Journal`] is dropped
+async fn run_writer(file: tokio::fs::File, mut writer_rx: mpsc::UnboundedReceiver<WriterMessage>) {
+    let mut writer = BufWriter::new(file);
+    while let Some(message) = writer_rx.recv().await {
+        match message {
+            WriterMessage::Entry(entry) => {
+                if let Err(err) = writer
+                    .write_all(&entry.encode_length_delimited_to_vec())
+                    .await
+                {
+                    error!(%err, "Failed to write entry to journal");
+                    metrics::JOURNAL_WRITE_ERRORS.inc();
+                }
+            }
+            WriterMessage::Flush(done_tx) => {
+                let _ = done_tx.send(writer.flush().await);
+            }
+        }
+
+        // Flushing once nothing is queued anymore (instead of after every entry) keeps up with bursts of paintings,
+        // while entries still survive a crash of the server shortly after they were written
+        if writer_rx.is_empty() {
+            if let Err(err) = writer.flush().await {
+                error!(%err, "Failed to flush journal");
+                metrics::JOURNAL_WRITE_ERRORS.inc();
+            }
+        }
+    }
+}
+
+/// Reads the entries of a journal file in the order they were written.
+///
+/// The timestamps are wall-clock time, so they go backwards in case the clock of the server jumps backwards. The reader
+/// makes them monotonic by giving every entry at least the timestamp of the entry before it, so that everyone can rely
+/// on the entries being in chronological order (e.g. to stop replaying at a given point in time).
+pub struct JournalReader<R> {
+    reader: R,
+
+    /// Number of bytes that were read as part of complete entries
+    valid_len: u64,
+
+    /// Timestamp of the last entry that was read
+    last_painted_at_ms: u64,
+}
+
+impl JournalReader<BufReader<File>> {
+    pub fn open(file: &Path) -> anyhow::Result<Self> {
+        Self::open_at(file, 0, 0)
+    }
+
+    /// Opens the journal to read the entries starting at the given byte offset, which needs to be the start of an entry.
+    /// `last_painted_at_ms` is the timestamp of the entry before it.
+    fn open_at(file: &Path, offset: u64, last_painted_at_ms: u64) -> anyhow::Result<Self> {
+        let mut reader = File::open(file)
+            .with_context(|| format!("Failed to open journal file {}", file.display()))?;
+        reader
+            .seek(SeekFrom::Start(offset))
+            .with_context(|| format!("Failed to seek in journal file {}", file.display()))?;
+
+        Ok(Self {
+            reader: BufReader::new(reader),
+            valid_len: offset,
+            last_painted_at_ms,
+        })
+    }
+}
+
+impl<R: Read> JournalReader<R> {
+    pub fn valid_len(&self) -> u64 {
+        self.valid_len
+    }
+
+    /// Reads the next entry. Returns [`None`] at the end of the journal, which includes an incomplete last entry.
+    fn read_entry(&mut self) -> anyhow::Result<Option<JournalEntry>> {
+        let Some((len, len_bytes)) = self.read_len()? else {
+            return Ok(None);
+        };
+
+        if len > MAX_ENTRY_LEN {
+            bail!(
+                "Invalid length {len} of journal entry at byte offset {}, entries can not be longer than {MAX_ENTRY_LEN} bytes",
+                self.valid_len
+            );
+        }
+
+        let mut buffer = vec![0; len as usize];
+        match self.reader.read_exact(&mut buffer) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err).context("Failed to read journal entry"),
+        }
+
+        let mut entry = JournalEntry::decode(buffer.as_slice()).with_context(|| {
+            format!(
+                "Failed to decode journal entry at byte offset {}",
+                self.valid_len
+            )
+        })?;
+        self.valid_len += len_bytes + len;
+        entry.painted_at_ms = entry.painted_at_ms.max(self.last_painted_at_ms);
+        self.last_painted_at_ms = entry.painted_at_ms;
+
+        Ok(Some(entry))
+    }
+
+    /// Reads the varint length prefix of the next entry, returns the length as well as the number of bytes it took
+    fn read_len(&mut self) -> anyhow::Result<Option<(u64, u64)>> {
+        let mut len = 0;
+        for index in 0..10 {
+            let mut byte = [0];
+            match self.reader.read_exact(&mut byte) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err).context("Failed to read length of journal entry"),
+            }
+
+            len |= ((byte[0] & 0x7f) as u64) << (index * 7);
+            if byte[0] & 0x80 == 0 {
+                return Ok(Some((len, index + 1)));
+            }
+        }
+
+        bail!(
+            "Invalid length of journal entry at byte offset {}",
+            self.valid_len
+        )
+    }
+}
+
+impl<R: Read> Iterator for JournalReader<R> {
+    type Item = anyhow::Result<JournalEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+/// Replays all journal entries painted at or before `at_ms`, starting from the latest suitable checkpoint, and stores the
+/// result as a new checkpoint
+fn replay_from_checkpoint(
+    file: &Path,
+    checkpoints: &StdMutex<VecDeque<Checkpoint>>,
+    width: u16,
+    height: u16,
+    at_ms: u64,
+) -> anyhow::Result<FrameBuffer> {
+    let (mut framebuffer, offset, last_painted_at_ms) = {
+        let mut checkpoints = checkpoints.lock().expect("checkpoints lock poisoned");
+        if let Some(index) = checkpoints
+            .iter()
+            .position(|checkpoint| checkpoint.complete && checkpoint.at_ms == at_ms)
+        {
+            // Move it to the back, so that frequently requested checkpoints are evicted last
+            let checkpoint = checkpoints.remove(index).expect("index is within bounds");
+            let framebuffer = checkpoint.framebuffer.clone();
+            checkpoints.push_back(checkpoint);
+            return Ok(framebuffer);
+        }
+
+        latest_checkpoint(&checkpoints, width, height, Some(at_ms))
+    };
+
+    if !file.exists() {
+        return Ok(framebuffer);
+    }
+
+    let mut reader = JournalReader::open_at(file, offset, last_painted_at_ms)?;
+    let complete = loop {
+        let position = (reader.valid_len, reader.last_painted_at_ms);
+        let Some(entry) = reader.next() else {
+            break false;
+        };
+        let entry = entry.context("Failed to read journal")?;
+        // The reader made the timestamps monotonic, so there is nothing more to replay
+        if entry.painted_at_ms > at_ms {
+            (reader.valid_len, reader.last_painted_at_ms) = position;
+            break true;
+        }
+
+        if let Some(painting) = entry.painting {
+            framebuffer.apply_painted(&painting.painted);
+        }
+    };
+
+    let mut checkpoints = checkpoints.lock().expect("checkpoints lock poisoned");
+    checkpoints.push_back(Checkpoint {
+        at_ms,
+        offset: reader.valid_len,
+        last_painted_at_ms: reader.last_painted_at_ms,
+        complete,
+        framebuffer: framebuffer.clone(),
+    });
+    if checkpoints.len() > MAX_CHECKPOINTS {
+        checkpoints.pop_front();
+    }
+
+    Ok(framebuffer)
+}
+
+/// Returns the canvas, byte offset and last timestamp of the latest checkpoint at or before `at_ms`, or an empty canvas
+/// at the start of the journal in case there is none (or `at_ms` is [`None`])
+fn latest_checkpoint(
+    checkpoints: &VecDeque<Checkpoint>,
+    width: u16,
+    height: u16,
+    at_ms: Option<u64>,
+) -> (FrameBuffer, u64, u64) {
+    match checkpoints
+        .iter()
+        .filter(|checkpoint| at_ms.is_some_and(|at_ms| checkpoint.at_ms <= at_ms))
+        .max_by_key(|checkpoint| checkpoint.offset)
+    {
+        Some(checkpoint) => (
+            checkpoint.framebuffer.clone(),
+            checkpoint.offset,
+            checkpoint.last_painted_at_ms,
+        ),
+        None => (FrameBuffer::new(width, height), 0, 0),
+    }
+}
+
+/// A pixel whose last change was made by the rolled back client, see [`Journal::rollback_client`]
+pub struct RolledBackPixel {
+    pub x: u16,
+    pub y: u16,
+
+    /// Color of the pixel before the client painted it
+    pub rgba: u32,
+
+    /// Color the client painted, the pixel is only rolled back in case it still has this color
+    pub painted_rgba: u32,
+}
+
+/// See [`Journal::rollback_client`]
+fn rollback_from_checkpoint(
+    file: &Path,
+    checkpoints: &StdMutex<VecDeque<Checkpoint>>,
+    width: u16,
+    height: u16,
+    client: &str,
+    since_ms: u64,
+) -> anyhow::Result<Vec<RolledBackPixel>> {
+    // Everything painted before `since_ms` only matters for the colors the pixels had before, so we can start from any
+    // checkpoint that does not contain an entry painted at `since_ms` or later
+    let (mut framebuffer, offset, last_painted_at_ms) = {
+        let checkpoints = checkpoints.lock().expect("checkpoints lock poisoned");
+        latest_checkpoint(&checkpoints, width, height, since_ms.checked_sub(1))
+    };
+
+    // The color each pixel had before the client started painting it, as long as the client was the last one painting it
+    let mut previous_colors = HashMap::new();
+
+    if file.exists() {
+        for entry in JournalReader::open_at(file, offset, last_painted_at_ms)? {
+            let entry = entry.context("Failed to read journal")?;
+            let Some(painting) = entry.painting else {
+                continue;
+            };
+            let rolled_back = painting.client == client && entry.painted_at_ms >= since_ms;
+
+            let mut painted = painting.painted.as_slice();
+            while painted.remaining() >= 8
+            /* bytes per pixel */
+            {
+                let x = painted.get_u16();
+                let y = painted.get_u16();
+                painted.advance(4 /* rgba */);
+
+                if !rolled_back {
+                    previous_colors.remove(&(x, y));
+                } else if let Some(rgba) = framebuffer.get(x, y) {
+                    // Keep the color from before the first of multiple consecutive paints of the client
+                    previous_colors.entry((x, y)).or_insert(rgba);
+                }
+            }
+
+            framebuffer.apply_painted(&painting.painted);
+        }
+    }
+
+    Ok(previous_colors
+        .into_iter()
+        .filter_map(|((x, y), rgba)| {
+            let painted_rgba = framebuffer.get(x, y)?;
+            Some(RolledBackPixel {
+                x,
+                y,
+                rgba,
+                painted_rgba,
+            })
+        })
+        .collect())
+}
+
+pub fn unix_millis_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::*;
+    use crate::framebuffer::PixelUpdate;
+
+    /// Journal file in the temp dir, which is removed once dropped
+    struct TempJournal(PathBuf);
+
+    impl TempJournal {
+        fn new(name: &str) -> Self {
+            let file = std::env::temp_dir()
+                .join(format!("pixelstrom-{}-{name}.journal", std::process::id()));
+            let _ = fs::remove_file(&file);
+            Self(file)
+        }
+    }
+
+    impl Drop for TempJournal {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn paint(journal: &Journal, painted_at_ms: u64, x: u16, rgba: u32) {
+        paint_as(journal, "alice", painted_at_ms, x, rgba);
+    }
+
+    fn paint_as(journal: &Journal, client: &str, painted_at_ms: u64, x: u16, rgba: u32) {
+        let painting = FrameBuffer::new(2, 1).set_multi(
+            client,
+            &[PixelUpdate {
+                x,
+                y: 0,
+                rgba,
+                alpha: u8::MAX,
+            }],
+        );
+        journal.append(painted_at_ms, &painting);
+    }
+
+    async fn pixels_at(journal: &Journal, at_ms: u64) -> [u32; 2] {
+        let framebuffer = journal.canvas_at(2, 1, at_ms).await.unwrap();
+        [0, 1].map(|x| framebuffer.get(x, 0).unwrap())
+    }
+
+    #[tokio::test]
+    async fn canvas_at_continues_from_checkpoints() {
+        let file = TempJournal::new("checkpoints");
+        let journal = Journal::recover(&file.0, &mut FrameBuffer::new(2, 1), u64::MAX).unwrap();
+        paint(&journal, 10, 0, 0x11);
+        paint(&journal, 20, 0, 0x22);
+        paint(&journal, 30, 1, 0x33);
+
+        assert_eq!(pixels_at(&journal, 25).await, [0x22, 0]);
+        // Earlier than all checkpoints, so this replays from the start
+        assert_eq!(pixels_at(&journal, 15).await, [0x11, 0]);
+        assert_eq!(pixels_at(&journal, 25).await, [0x22, 0]);
+        assert_eq!(pixels_at(&journal, 40).await, [0x22, 0x33]);
+
+        // The checkpoint at 40 reached the end of the journal, so entries appended afterwards are still replayed
+        paint(&journal, 50, 0, 0x55);
+        assert_eq!(pixels_at(&journal, 40).await, [0x22, 0x33]);
+        assert_eq!(pixels_at(&journal, 60).await, [0x55, 0x33]);
+        assert_eq!(pixels_at(&journal, 5).await, [0, 0]);
+    }
+
+    #[test]
+    fn corrupt_entry_length_is_rejected() {
+        let file = TempJournal::new("corrupt");
+        let mut journal = File::create(&file.0).unwrap();
+        // Varint of 2^40
+        journal
+            .write_all(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x20, 0x01, 0x02])
+            .unwrap();
+        drop(journal);
+
+        let err = JournalReader::open(&file.0)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid length"), "{err:?}");
+        assert!(Journal::recover(&file.0, &mut FrameBuffer::new(2, 1), 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn clock_jumping_backwards() {
+        let file = TempJournal::new("clock");
+        let journal = Journal::recover(&file.0, &mut FrameBuffer::new(2, 1), u64::MAX).unwrap();
+        paint(&journal, 100, 0, 0x11);
+        // The clock jumped back, the entry is treated as if it was painted at 100
+        paint(&journal, 50, 1, 0x22);
+        paint(&journal, 60, 0, 0x33);
+
+        assert_eq!(pixels_at(&journal, 99).await, [0, 0]);
+        assert_eq!(pixels_at(&journal, 100).await, [0x33, 0x22]);
+        // Continues from the checkpoint at 100, which needs to remember the last timestamp
+        paint(&journal, 70, 1, 0x44);
+        assert_eq!(pixels_at(&journal, 100).await, [0x33, 0x44]);
+
+        journal.flush().await.unwrap();
+        let timestamps: Vec<_> = JournalReader::open(&file.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().painted_at_ms)
+            .collect();
+        assert_eq!(timestamps, [100, 100, 100, 100]);
+    }
+
+    /// Rolls back bob and returns the colors to restore as `(x, rgba)`
+    async fn rollback_bob(journal: &Journal, since_ms: u64) -> Vec<(u16, u32)> {
+        let mut rolled_back: Vec<_> = journal
+            .rollback_client(2, 1, "bob", since_ms)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|pixel| (pixel.x, pixel.rgba))
+            .collect();
+        rolled_back.sort();
+        rolled_back
+    }
+
+    #[tokio::test]
+    async fn rollback_restores_the_colors_from_before() {
+        let file = TempJournal::new("rollback");
+        let journal = Journal::recover(&file.0, &mut FrameBuffer::new(2, 1), u64::MAX).unwrap();
+        paint_as(&journal, "alice", 10, 0, 0x11);
+        paint_as(&journal, "bob", 15, 0, 0x99);
+        paint_as(&journal, "alice", 20, 0, 0x22);
+        paint_as(&journal, "bob", 30, 0, 0x33);
+        paint_as(&journal, "bob", 40, 0, 0x44);
+        paint_as(&journal, "bob", 40, 1, 0x55);
+
+        // The color from before the first paint of bob within the window
+        assert_eq!(rollback_bob(&journal, 25).await, [(0, 0x22), (1, 0)]);
+        // Starting from a checkpoint before the window gives the same result
+        assert_eq!(pixels_at(&journal, 20).await, [0x22, 0]);
+        assert_eq!(rollback_bob(&journal, 25).await, [(0, 0x22), (1, 0)]);
+        // Alice painted over the first paint of bob, so only the later ones are rolled back
+        assert_eq!(rollback_bob(&journal, 0).await, [(0, 0x22), (1, 0)]);
+    }
+
+    #[tokio::test]
+    async fn rollback_restores_the_canvas_the_journal_started_with() {
+        let file = TempJournal::new("rollback-base");
+        // E.g. restored from a snapshot taken while the journal was disabled
+        let mut framebuffer = FrameBuffer::new(2, 1);
+        framebuffer.set_multi(
+            "alice",
+            &[PixelUpdate {
+                x: 1,
+                y: 0,
+                rgba: 0x77,
+                alpha: u8::MAX,
+            }],
+        );
+        let journal = Journal::recover(&file.0, &mut framebuffer, 5).unwrap();
+        paint_as(&journal, "bob", 10, 1, 0x88);
+
+        assert_eq!(rollback_bob(&journal, 0).await, [(1, 0x77)]);
+        assert_eq!(pixels_at(&journal, 5).await, [0, 0x77]);
+        assert_eq!(pixels_at(&journal, 10).await, [0, 0x88]);
+    }
+}
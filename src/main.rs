@@ -2,20 +2,30 @@ use std::{ops::Deref, sync::Arc, time::Duration};
 
 use anyhow::Context;
 use ascii_server::AsciiServer;
+use clap::Parser;
 use prost::bytes::BufMut;
 use rand::Rng;
-use tokio::{sync::mpsc, time::interval};
+use tokio::{select, signal, sync::mpsc, time::interval};
+use tracing::info;
 
 use crate::{
     app_state::AppState,
-    http_server::{run_http_server, websocket::start_websocket_compressor_loop},
+    config::{Args, Command, Config},
+    http_server::{admin::AdminState, run_http_server, websocket::start_websocket_compressor_loop},
     proto::{web_socket_message::Payload, ClientPainting, WebSocketMessage},
+    snapshot::{start_snapshot_loop, write_snapshot},
 };
 
 mod app_state;
 mod ascii_server;
+mod config;
 mod framebuffer;
 mod http_server;
+mod journal;
+mod metrics;
+mod png_export;
+mod snapshot;
+mod timelapse;
 
 mod proto {
     include!(concat!(env!("OUT_DIR"), "/pixelstrom.rs"));
@@ -25,42 +35,89 @@ mod proto {
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
-    let width = 1920;
-    let height = 1080;
-    let ascii_listener_address = "[::]:1234";
-    let http_listener_address = "[::]:3000";
-    let max_pixels_per_slot = 5_000;
-    let slot_duration = Duration::from_millis(500);
+    let args = Args::parse();
+    let config = Config::load(&args).context("Failed to load configuration")?;
+
+    match &args.command {
+        None | Some(Command::Serve) => metrics::init(),
+        Some(Command::Timelapse(timelapse_args)) => {
+            return timelapse::render_timelapse(&config, timelapse_args)
+                .context("Failed to render timelapse");
+        }
+    }
 
     // This only buffers between the server and the compression loop
     // There is a separate broadcast channel between the compression loop and individual websockets
     let (ws_message_tx, ws_message_rx) = mpsc::channel(32);
     let compressed_ws_message_rx = start_websocket_compressor_loop(ws_message_rx).await;
 
-    let app_state = AppState::new(width, height, ws_message_tx, compressed_ws_message_rx);
+    let app_state = AppState::new(&config, ws_message_tx, compressed_ws_message_rx)
+        .context("Failed to create app state")?;
     let shared_state = Arc::new(app_state);
 
+    if config.snapshot.enabled {
+        start_snapshot_loop(shared_state.clone(), config.snapshot.clone());
+    }
+
     // let shared_state_clone = shared_state.clone();
     // tokio::spawn(async move { rainbow_loop(shared_state_clone).await });
 
     // let ws_message_tx_clone = shared_state.ws_message_tx.clone();
+    // let (width, height) = (config.canvas.width, config.canvas.height);
     // tokio::spawn(
     //     async move { random_client_paints_loop(width, height, ws_message_tx_clone).await },
     // );
 
-    let ascii_server = AsciiServer::new(
-        shared_state.clone(),
-        ascii_listener_address,
-        max_pixels_per_slot,
-        slot_duration,
-        width,
-        height,
-    )
-    .await
-    .context("Failed to start ASCII server")?;
+    let ascii_server = AsciiServer::new(shared_state.clone(), &config)
+        .await
+        .context("Failed to start ASCII server")?;
+    let admin_state = AdminState {
+        shared_state: shared_state.clone(),
+        user_scheduler: ascii_server.user_scheduler(),
+        violation_tracker: ascii_server.violation_tracker(),
+        connections_per_ip: ascii_server.connections_per_ip(),
+    };
     tokio::spawn(async move { ascii_server.run().await });
 
-    run_http_server(shared_state, http_listener_address).await?;
+    let result = select! {
+        result = run_http_server(shared_state.clone(), admin_state, &config.http_server) => result,
+        result = shutdown_signal() => {
+            info!("Received shutdown signal, shutting down");
+            result
+        }
+    };
+
+    // Also persist the canvas in case the HTTP server failed, so that we don't lose anything
+    if let Some(journal) = &shared_state.journal {
+        journal
+            .flush()
+            .await
+            .context("Failed to flush journal on shutdown")?;
+    }
+    if config.snapshot.enabled {
+        write_snapshot(&shared_state, &config.snapshot)
+            .await
+            .context("Failed to write snapshot on shutdown")?;
+    }
+
+    result
+}
+
+/// Waits until we are asked to shut down, either via Ctrl+C or SIGTERM
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .context("Failed to install SIGTERM handler")?;
+        select! {
+            result = signal::ctrl_c() => result.context("Failed to listen for Ctrl+C")?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c()
+        .await
+        .context("Failed to listen for Ctrl+C")?;
 
     Ok(())
 }
@@ -0,0 +1,138 @@
+//! Prometheus metrics, exposed by the HTTP server at `/metrics`. See METRICS.md for an overview.
+
+use std::sync::LazyLock;
+
+use prometheus::{
+    exponential_buckets, register_histogram, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+pub static ASCII_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "pixelstrom_ascii_connections",
+        "Number of open ASCII connections"
+    )
+    .expect("metric can be registered")
+});
+
+pub static LOGGED_IN_USERS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "pixelstrom_logged_in_users",
+        "Number of users logged in and waiting for or having a slot"
+    )
+    .expect("metric can be registered")
+});
+
+pub static PAINTED_PIXELS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "pixelstrom_painted_pixels_total",
+        "Number of pixels painted by the users"
+    )
+    .expect("metric can be registered")
+});
+
+pub static SLOTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("pixelstrom_slots_total", "Number of slots given to users")
+        .expect("metric can be registered")
+});
+
+pub static LATE_DONES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "pixelstrom_late_dones_total",
+        "Number of DONEs received during the grace period after the end of the slot"
+    )
+    .expect("metric can be registered")
+});
+
+pub static VIOLATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "pixelstrom_violations_total",
+        "Number of rule violations by kind, slot misses are counted as slot_not_closed_in_time",
+        &["kind"]
+    )
+    .expect("metric can be registered")
+});
+
+pub static WEBSOCKET_VIEWERS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "pixelstrom_websocket_viewers",
+        "Number of connected websockets"
+    )
+    .expect("metric can be registered")
+});
+
+pub static WEBSOCKET_LAG_CLOSES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "pixelstrom_websocket_lag_closes_total",
+        "Number of websockets closed because they lagged behind too much"
+    )
+    .expect("metric can be registered")
+});
+
+pub static JOURNAL_WRITE_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "pixelstrom_journal_write_errors_total",
+        "Number of paintings that could not be written to the paint journal"
+    )
+    .expect("metric can be registered")
+});
+
+pub static WEBSOCKET_QUEUE_LENGTH: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "pixelstrom_websocket_queue_length",
+        "Number of messages queued in the broadcast channel for a websocket when it receives the next message",
+        exponential_buckets(1.0, 2.0, 10).expect("valid buckets")
+    )
+    .expect("metric can be registered")
+});
+
+pub static WEBSOCKET_COMPRESSION_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "pixelstrom_websocket_compression_seconds",
+        "Time it took to encode and compress a websocket message",
+        exponential_buckets(0.0001, 4.0, 8).expect("valid buckets")
+    )
+    .expect("metric can be registered")
+});
+
+pub static WEBSOCKET_COMPRESSION_RATIO: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "pixelstrom_websocket_compression_ratio",
+        "Uncompressed size divided by the compressed size of websocket messages",
+        exponential_buckets(1.0, 2.0, 10).expect("valid buckets")
+    )
+    .expect("metric can be registered")
+});
+
+/// Registers all metrics, so that they are exported (with their initial value) before they are used the first time
+pub fn init() {
+    LazyLock::force(&ASCII_CONNECTIONS);
+    LazyLock::force(&LOGGED_IN_USERS);
+    LazyLock::force(&PAINTED_PIXELS);
+    LazyLock::force(&SLOTS);
+    LazyLock::force(&LATE_DONES);
+    LazyLock::force(&WEBSOCKET_VIEWERS);
+    LazyLock::force(&WEBSOCKET_LAG_CLOSES);
+    LazyLock::force(&JOURNAL_WRITE_ERRORS);
+    LazyLock::force(&WEBSOCKET_QUEUE_LENGTH);
+    LazyLock::force(&WEBSOCKET_COMPRESSION_SECONDS);
+    LazyLock::force(&WEBSOCKET_COMPRESSION_RATIO);
+
+    // Labeled metrics are only exported once a label value was used
+    for kind in [
+        "quota_exceeded",
+        "not_your_slot",
+        "pixel_after_done",
+        "slot_not_closed_in_time",
+    ] {
+        VIOLATIONS.with_label_values(&[kind]);
+    }
+}
+
+/// Renders all metrics in the Prometheus text format
+pub fn render() -> anyhow::Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
@@ -0,0 +1,21 @@
+use anyhow::Context;
+use png::{BitDepth, ColorType, Encoder};
+
+/// Encodes the given RGB bytes (see [`crate::framebuffer::FrameBuffer::region_rgb`]) as PNG image
+pub fn encode_png(rgb: &[u8], width: u32, height: u32) -> anyhow::Result<Vec<u8>> {
+    let mut png = Vec::new();
+
+    let mut encoder = Encoder::new(&mut png, width, height);
+    encoder.set_color(ColorType::Rgb);
+    encoder.set_depth(BitDepth::Eight);
+
+    let mut writer = encoder
+        .write_header()
+        .context("Failed to write PNG header")?;
+    writer
+        .write_image_data(rgb)
+        .context("Failed to write PNG image data")?;
+    writer.finish().context("Failed to finish PNG image")?;
+
+    Ok(png)
+}
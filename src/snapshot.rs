@@ -0,0 +1,335 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Write,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{bail, Context};
+use prost::Message;
+use tokio::time::interval;
+use tracing::{debug, error, info, warn};
+use zstd::DEFAULT_COMPRESSION_LEVEL;
+
+use crate::{
+    app_state::AppState,
+    config::{CanvasConfig, DimensionMismatchPolicy, SnapshotConfig},
+    framebuffer::FrameBuffer,
+    journal::unix_millis_now,
+    proto::CanvasSnapshot,
+};
+
+const ZSTD_COMPRESSION_LEVEL: i32 = DEFAULT_COMPRESSION_LEVEL;
+
+pub enum RestoredSnapshot {
+    /// The canvas from the snapshot, which always has the configured canvas dimensions
+    Canvas {
+        framebuffer: FrameBuffer,
+        taken_at_ms: u64,
+    },
+
+    /// Snapshots are disabled or there is no snapshot yet
+    Missing,
+
+    /// The snapshot was discarded because of the [`DimensionMismatchPolicy`], so we start with an empty canvas. The
+    /// journal needs to be discarded as well, as it only contains the changes on top of the snapshot.
+    Discarded,
+}
+
+/// Restores the canvas from the snapshot file
+pub fn restore_snapshot(
+    config: &SnapshotConfig,
+    canvas: &CanvasConfig,
+) -> anyhow::Result<RestoredSnapshot> {
+    if !config.enabled || !config.file.exists() {
+        return Ok(RestoredSnapshot::Missing);
+    }
+
+    let file = config.file.display();
+    let compressed =
+        fs::read(&config.file).with_context(|| format!("Failed to read snapshot file {file}"))?;
+    let uncompressed = zstd::decode_all(compressed.as_slice())
+        .with_context(|| format!("Failed to decompress snapshot file {file}"))?;
+    let snapshot = CanvasSnapshot::decode(uncompressed.as_slice())
+        .with_context(|| format!("Failed to decode snapshot file {file}"))?;
+    let screen = snapshot
+        .screen
+        .with_context(|| format!("The snapshot file {file} contains no screen"))?;
+    let restored = FrameBuffer::try_from(&screen)
+        .with_context(|| format!("The snapshot file {file} contains an invalid screen"))?;
+
+    let taken_at_ms = snapshot.taken_at_ms;
+    let (width, height) = (restored.width(), restored.height());
+    if width == canvas.width && height == canvas.height {
+        info!(%file, taken_at_ms, "Restored canvas from snapshot");
+        return Ok(RestoredSnapshot::Canvas {
+            framebuffer: restored,
+            taken_at_ms,
+        });
+    }
+
+    match config.on_dimension_mismatch {
+        DimensionMismatchPolicy::Crop => {
+            warn!(
+                %file, taken_at_ms, width, height, canvas.width, canvas.height,
+                "The snapshot has different dimensions than the canvas, only restoring the overlapping part"
+            );
+
+            let mut framebuffer = FrameBuffer::new(canvas.width, canvas.height);
+            framebuffer.copy_overlapping_from(&restored);
+            Ok(RestoredSnapshot::Canvas {
+                framebuffer,
+                taken_at_ms,
+            })
+        }
+        DimensionMismatchPolicy::Discard => {
+            let discarded_file = path_with_suffix(&config.file, ".discarded");
+            warn!(
+                %file, taken_at_ms, width, height, canvas.width, canvas.height,
+                discarded_file = %discarded_file.display(),
+                "The snapshot has different dimensions than the canvas, starting with an empty canvas"
+            );
+
+            fs::rename(&config.file, &discarded_file).with_context(|| {
+                format!(
+                    "Failed to move discarded snapshot file {file} to {}",
+                    discarded_file.display()
+                )
+            })?;
+            Ok(RestoredSnapshot::Discarded)
+        }
+        DimensionMismatchPolicy::Fail => bail!(
+            "The snapshot file {file} has the dimensions {width}x{height}, but the canvas is configured as {}x{}. \
+            Either change the canvas size or the snapshot.on_dimension_mismatch policy",
+            canvas.width,
+            canvas.height
+        ),
+    }
+}
+
+/// Writes the current canvas to the snapshot file.
+///
+/// The snapshot is written to a temporary file first, which is renamed afterwards. This way we never end up with a
+/// half-written snapshot, even if we crash while writing it.
+pub async fn write_snapshot(
+    shared_state: &AppState,
+    config: &SnapshotConfig,
+) -> anyhow::Result<()> {
+    let snapshot = {
+        let framebuffer = shared_state.framebuffer.read().await;
+        // Paintings are journaled while holding the framebuffer write lock, so determining the timestamp while holding
+        // the read lock makes sure it is consistent with the journal.
+        CanvasSnapshot {
+            taken_at_ms: unix_millis_now(),
+            screen: Some(framebuffer.deref().into()),
+        }
+    };
+
+    let file = config.file.clone();
+    // Compressing and writing can take a while, so we put it on the blocking threadpool
+    tokio::task::spawn_blocking(move || write_snapshot_file(&snapshot, &file))
+        .await
+        .context("Failed to join task that writes snapshot")?
+}
+
+/// Periodically writes a snapshot of the canvas to disk
+pub fn start_snapshot_loop(shared_state: Arc<AppState>, config: SnapshotConfig) {
+    tokio::spawn(async move {
+        let mut interval = interval(config.interval);
+        // The first tick completes immediately, but there is nothing new to persist at startup
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = write_snapshot(&shared_state, &config).await {
+                error!(error = ?err, "Failed to write snapshot");
+            }
+        }
+    });
+}
+
+fn write_snapshot_file(snapshot: &CanvasSnapshot, file: &Path) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let uncompressed_bytes = snapshot.encode_to_vec();
+    let compressed_bytes = zstd::encode_all(uncompressed_bytes.as_slice(), ZSTD_COMPRESSION_LEVEL)
+        .context("Failed to compress snapshot")?;
+
+    let tmp_file = path_with_suffix(file, ".tmp");
+    let mut tmp = File::create(&tmp_file).with_context(|| {
+        format!(
+            "Failed to create temporary snapshot file {}",
+            tmp_file.display()
+        )
+    })?;
+    tmp.write_all(&compressed_bytes).with_context(|| {
+        format!(
+            "Failed to write to temporary snapshot file {}",
+            tmp_file.display()
+        )
+    })?;
+    tmp.sync_all().with_context(|| {
+        format!(
+            "Failed to sync temporary snapshot file {}",
+            tmp_file.display()
+        )
+    })?;
+
+    fs::rename(&tmp_file, file).with_context(|| {
+        format!(
+            "Failed to rename temporary snapshot file {} to {}",
+            tmp_file.display(),
+            file.display()
+        )
+    })?;
+
+    debug!(
+        file = %file.display(),
+        compressed_bytes = compressed_bytes.len(),
+        uncompressed_bytes = uncompressed_bytes.len(),
+        duration = ?start.elapsed(),
+        "Written snapshot"
+    );
+
+    Ok(())
+}
+
+pub fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::*;
+    use crate::{config::Config, framebuffer::PixelUpdate, journal::Journal};
+
+    /// Directory in the temp dir, which is removed once dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("pixelstrom-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes a 2x2 snapshot with a pixel in the top left and the bottom right corner and returns a config with a 3x1
+    /// canvas
+    fn mismatching_snapshot(dir: &TempDir, policy: DimensionMismatchPolicy) -> Config {
+        let mut config = Config::default();
+        config.snapshot.enabled = true;
+        config.snapshot.file = dir.0.join("canvas.snapshot");
+        config.snapshot.on_dimension_mismatch = policy;
+        config.journal.enabled = false;
+        config.journal.file = dir.0.join("paint.journal");
+        config.canvas.width = 3;
+        config.canvas.height = 1;
+
+        let mut framebuffer = FrameBuffer::new(2, 2);
+        framebuffer.set_multi(
+            "alice",
+            &[(0, 0, 0x11), (1, 1, 0x22)].map(|(x, y, rgba)| PixelUpdate {
+                x,
+                y,
+                rgba,
+                alpha: u8::MAX,
+            }),
+        );
+        let snapshot = CanvasSnapshot {
+            taken_at_ms: 42,
+            screen: Some((&framebuffer).into()),
+        };
+        write_snapshot_file(&snapshot, &config.snapshot.file).unwrap();
+
+        config
+    }
+
+    #[test]
+    fn crop_restores_the_overlapping_part() {
+        let dir = TempDir::new("crop");
+        let config = mismatching_snapshot(&dir, DimensionMismatchPolicy::Crop);
+
+        let RestoredSnapshot::Canvas {
+            framebuffer,
+            taken_at_ms,
+        } = restore_snapshot(&config.snapshot, &config.canvas).unwrap()
+        else {
+            panic!("the snapshot should be restored");
+        };
+        assert_eq!(taken_at_ms, 42);
+        assert_eq!((framebuffer.width(), framebuffer.height()), (3, 1));
+        assert_eq!(
+            [0, 1, 2].map(|x| framebuffer.get(x, 0).unwrap()),
+            [0x11, 0, 0]
+        );
+    }
+
+    #[test]
+    fn discard_moves_the_snapshot_aside() {
+        let dir = TempDir::new("discard");
+        let config = mismatching_snapshot(&dir, DimensionMismatchPolicy::Discard);
+
+        assert!(matches!(
+            restore_snapshot(&config.snapshot, &config.canvas).unwrap(),
+            RestoredSnapshot::Discarded
+        ));
+        assert!(!config.snapshot.file.exists());
+        assert!(path_with_suffix(&config.snapshot.file, ".discarded").exists());
+    }
+
+    #[test]
+    fn fail_keeps_the_snapshot() {
+        let dir = TempDir::new("fail");
+        let config = mismatching_snapshot(&dir, DimensionMismatchPolicy::Fail);
+
+        assert!(restore_snapshot(&config.snapshot, &config.canvas).is_err());
+        assert!(config.snapshot.file.exists());
+    }
+
+    #[tokio::test]
+    async fn discard_starts_with_an_empty_canvas_despite_the_journal() {
+        let dir = TempDir::new("discard-journal");
+        let mut config = mismatching_snapshot(&dir, DimensionMismatchPolicy::Discard);
+        config.journal.enabled = true;
+        {
+            // Paintings on top of the discarded snapshot
+            let journal =
+                Journal::recover(&config.journal.file, &mut FrameBuffer::new(2, 2), 0).unwrap();
+            let painting = FrameBuffer::new(2, 2).set_multi(
+                "alice",
+                &[PixelUpdate {
+                    x: 1,
+                    y: 0,
+                    rgba: 0x33,
+                    alpha: u8::MAX,
+                }],
+            );
+            journal.append(100, &painting);
+            journal.flush().await.unwrap();
+        }
+
+        let (ws_message_tx, _) = mpsc::channel(1);
+        let (_, compressed_ws_message_rx) = broadcast::channel(1);
+        let app_state = AppState::new(&config, ws_message_tx, compressed_ws_message_rx).unwrap();
+
+        let framebuffer = app_state.framebuffer.read().await;
+        assert_eq!([0, 1, 2].map(|x| framebuffer.get(x, 0).unwrap()), [0, 0, 0]);
+        assert!(path_with_suffix(&config.journal.file, ".discarded").exists());
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    iter::Peekable,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context};
+use clap::{Args, ValueEnum};
+use png::{BitDepth, ColorType, Encoder};
+use tracing::info;
+
+use crate::{
+    config::Config, framebuffer::FrameBuffer, journal::JournalReader, png_export::encode_png,
+    proto::JournalEntry,
+};
+
+/// Upper bound for the number of frames, so that a too small interval fails fast instead of rendering for ages
+const MAX_FRAMES: u64 = 100_000;
+
+#[derive(Debug, Args)]
+pub struct TimelapseArgs {
+    /// Where to write the timelapse to. A file for `apng`, a directory for `png-sequence`.
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Format of the timelapse
+    #[arg(short, long, value_enum, default_value_t = TimelapseFormat::Apng)]
+    format: TimelapseFormat,
+
+    /// Paint journal to render the timelapse from. Defaults to the journal file from the configuration.
+    #[arg(short, long)]
+    journal: Option<PathBuf>,
+
+    /// Amount of (real) time between two frames, e.g. `30s` or `5m`
+    #[arg(short, long, default_value = "1m", value_parser = humantime::parse_duration)]
+    interval: Duration,
+
+    /// How long every frame is shown in the animated image, e.g. `100ms`
+    #[arg(long, default_value = "100ms", value_parser = humantime::parse_duration)]
+    frame_delay: Duration,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TimelapseFormat {
+    /// Single animated PNG
+    Apng,
+
+    /// Directory of numbered PNG images
+    PngSequence,
+}
+
+/// Renders a timelapse of the canvas from the paint journal.
+///
+/// Frame `n` shows the canvas as it looked like `n * interval` after the first painting. The canvas has the configured
+/// dimensions.
+pub fn render_timelapse(config: &Config, args: &TimelapseArgs) -> anyhow::Result<()> {
+    ensure!(
+        !args.interval.is_zero(),
+        "The interval needs to be greater than zero"
+    );
+    let interval_ms = args.interval.as_millis() as u64;
+    let journal = args.journal.as_ref().unwrap_or(&config.journal.file);
+
+    // We need to know the number of frames upfront for APNG, so we have a quick look at the first and last timestamp
+    let mut timestamps =
+        JournalReader::open(journal)?.map(|entry| entry.map(|entry| entry.painted_at_ms));
+    let Some(first_ms) = timestamps
+        .next()
+        .transpose()
+        .context("Failed to read journal")?
+    else {
+        bail!("The journal {} contains no entries", journal.display());
+    };
+    // The reader makes the timestamps monotonic, even in case the clock of the server jumped backwards
+    let mut last_ms = first_ms;
+    for timestamp in timestamps {
+        last_ms = timestamp.context("Failed to read journal")?;
+    }
+    let num_frames = (last_ms - first_ms).div_ceil(interval_ms).max(1);
+    ensure!(
+        num_frames <= MAX_FRAMES,
+        "The timelapse would have {num_frames} frames, but at most {MAX_FRAMES} are supported, please increase the interval"
+    );
+    let num_frames = num_frames as u32;
+
+    info!(
+        journal = %journal.display(),
+        num_frames,
+        ?args.format,
+        "Rendering timelapse"
+    );
+
+    let mut frames = Frames {
+        entries: JournalReader::open(journal)?.peekable(),
+        framebuffer: FrameBuffer::new(config.canvas.width, config.canvas.height),
+        next_frame_at_ms: first_ms,
+        interval_ms,
+    };
+
+    match args.format {
+        TimelapseFormat::Apng => write_apng(&mut frames, num_frames, args),
+        TimelapseFormat::PngSequence => write_png_sequence(&mut frames, num_frames, args),
+    }
+}
+
+/// Advances the canvas frame by frame
+struct Frames<I: Iterator<Item = anyhow::Result<JournalEntry>>> {
+    entries: Peekable<I>,
+    framebuffer: FrameBuffer,
+    next_frame_at_ms: u64,
+    interval_ms: u64,
+}
+
+impl<I: Iterator<Item = anyhow::Result<JournalEntry>>> Frames<I> {
+    /// Applies all entries until the next frame and returns the RGB bytes of the frame
+    fn next_frame(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.next_frame_at_ms += self.interval_ms;
+
+        while let Some(entry) = self.entries.next_if(|entry| {
+            entry
+                .as_ref()
+                .map_or(true, |entry| entry.painted_at_ms <= self.next_frame_at_ms)
+        }) {
+            if let Some(painting) = entry.context("Failed to read journal")?.painting {
+                self.framebuffer.apply_painted(&painting.painted);
+            }
+        }
+
+        let fb = &self.framebuffer;
+        Ok(fb.region_rgb(0, 0, fb.width(), fb.height(), 1))
+    }
+}
+
+fn write_apng<I: Iterator<Item = anyhow::Result<JournalEntry>>>(
+    frames: &mut Frames<I>,
+    num_frames: u32,
+    args: &TimelapseArgs,
+) -> anyhow::Result<()> {
+    let frame_delay_ms = u16::try_from(args.frame_delay.as_millis())
+        .context("The frame delay can be at most 65535ms")?;
+    let file = File::create(&args.output)
+        .with_context(|| format!("Failed to create output file {}", args.output.display()))?;
+
+    let mut encoder = Encoder::new(
+        BufWriter::new(file),
+        frames.framebuffer.width() as u32,
+        frames.framebuffer.height() as u32,
+    );
+    encoder.set_color(ColorType::Rgb);
+    encoder.set_depth(BitDepth::Eight);
+    encoder
+        .set_animated(num_frames, 0)
+        .context("Failed to enable APNG animation")?;
+    encoder
+        .set_frame_delay(frame_delay_ms, 1000)
+        .context("Failed to set APNG frame delay")?;
+
+    let mut writer = encoder
+        .write_header()
+        .context("Failed to write PNG header")?;
+    for frame in 0..num_frames {
+        writer
+            .write_image_data(&frames.next_frame()?)
+            .with_context(|| format!("Failed to write frame {frame}"))?;
+    }
+    writer.finish().context("Failed to finish APNG image")?;
+
+    info!(output = %args.output.display(), num_frames, "Written timelapse");
+
+    Ok(())
+}
+
+fn write_png_sequence<I: Iterator<Item = anyhow::Result<JournalEntry>>>(
+    frames: &mut Frames<I>,
+    num_frames: u32,
+    args: &TimelapseArgs,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(&args.output).with_context(|| {
+        format!(
+            "Failed to create output directory {}",
+            args.output.display()
+        )
+    })?;
+
+    let (width, height) = (
+        frames.framebuffer.width() as u32,
+        frames.framebuffer.height() as u32,
+    );
+    for frame in 0..num_frames {
+        let png = encode_png(&frames.next_frame()?, width, height)
+            .with_context(|| format!("Failed to encode frame {frame}"))?;
+
+        let file = args.output.join(format!("frame-{frame:06}.png"));
+        fs::write(&file, png)
+            .with_context(|| format!("Failed to write frame to {}", file.display()))?;
+    }
+
+    info!(output = %args.output.display(), num_frames, "Written timelapse");
+
+    Ok(())
+}